@@ -1,20 +1,31 @@
 #![feature(try_blocks)]
-use std::{path::PathBuf, str::FromStr};
+use std::{path::PathBuf, str::FromStr, time::Duration};
 
-use clap::{CommandFactory, Parser};
+use clap::{error::ErrorKind, CommandFactory, Parser};
 use clap_complete::generate;
 use culpa::throws;
 use derive_new::new;
-use eyre::Result;
+use eyre::{ContextCompat, Result};
 use goontunes::{
-    config::{AppConfig, ConfigCli},
+    config::{AppConfig, ConfigCli, PlaylistConfig},
+    database::{self, Database},
+    playlist,
     service::{
         self,
-        discord::{self, ScanSince},
-        spotify::{self, FetchPlaylist, FetchThing, Init},
+        discord::{self, Say, ScanSince},
+        spotify::{
+            self, FetchPlaylist, FetchThing, FetchThings, Init, RateLimitState, SaveToLibrary,
+            SyncPlaylist,
+        },
+    },
+    types::{
+        self,
+        chat::{ChatCommand, MessageBundle},
+    },
+    utils::{
+        pubsub::PUBSUB,
+        when_even::{Bug, Loggable, OnError},
     },
-    types::{self, chat::MessageBundle},
-    utils::pubsub::PUBSUB,
 };
 use kameo::{actor::ActorRef, message::Message, Actor};
 use serenity::all::ChannelId;
@@ -42,12 +53,40 @@ struct Cli {
 
     #[clap(long)]
     get: Vec<String>,
+
+    /// print what syncing each playlist would change, instead of syncing
+    #[clap(long, action)]
+    dry_run: bool,
+
+    /// print spotify links which couldn't be fetched, newest first
+    #[clap(long, action)]
+    failed: bool,
+}
+
+/// commands from chat, ie. `!dry-run my playlist`
+#[derive(Debug, Clone, Parser)]
+#[command(no_binary_name = true)]
+enum ChatCommands {
+    /// show what syncing a playlist would change
+    DryRun {
+        #[arg(required = true, num_args = 1..)]
+        playlist: Vec<String>,
+    },
+    /// list spotify links which couldn't be fetched
+    Failed {
+        #[arg(default_value_t = 20)]
+        n: usize,
+    },
+    /// whether spotify is rate limiting us, and how much is waiting
+    Status,
 }
 
 #[derive(new)]
 struct CoreActor {
     #[new(default)]
     this: Init<ActorRef<Self>>,
+    #[new(default)]
+    db: Init<Database>,
     config: AppConfig,
     cli: Cli,
 
@@ -64,6 +103,7 @@ impl kameo::Actor for CoreActor {
     #[instrument(skip_all, err)]
     async fn on_start(&mut self, actor_ref: ActorRef<Self>) {
         self.this.set(actor_ref);
+        self.db.set(database::init(self.config.database.clone()).await?);
 
         // So the hazard of this method is that this must be set up FIRST
         // or else we could drop things
@@ -71,10 +111,16 @@ impl kameo::Actor for CoreActor {
             .subscribe::<Vec<MessageBundle>, _>(self.this.get().clone())
             .await
             .unwrap();
+        PUBSUB
+            .subscribe::<ChatCommand, _>(self.this.get().clone())
+            .await
+            .unwrap();
 
         if let Some(conf) = self.config.spotify.get() {
             // TODO I don't like that it isn't a kameo function, wait for him to make prepare_with public
-            self.spotify = Some(crate::service::spotify::init_and_spawn(conf.clone()).await);
+            self.spotify = Some(
+                crate::service::spotify::init_and_spawn(conf.clone(), self.db.db.clone()).await,
+            );
         }
 
         if let Some(conf) = self.config.discord.get() {
@@ -95,15 +141,22 @@ impl kameo::Actor for CoreActor {
             }
         }
 
+        if self.cli.failed {
+            println!("{}", self.failed_fetches(100).await?);
+        }
+
         // TODO commands are simply actor messages
         for thing in self.cli.get.iter() {
-            let thing = self
+            let pl = self
                 .config
                 .playlists
                 .iter()
-                .find(|pl| pl.name.as_ref().map(|s| s.to_lowercase()) == Some(thing.to_lowercase()))
-                .map(|pl| pl.id.clone().unwrap())
-                .unwrap_or(thing.clone());
+                .find(|pl| pl.name.as_ref().map(|s| s.to_lowercase()) == Some(thing.to_lowercase()));
+            let thing = match pl {
+                Some(pl) => pl.resolve_id(&self.db.db).await?,
+                None => playlist::lookup_playlist_id(&self.db.db, thing).await?,
+            }
+            .unwrap_or(thing.clone());
 
             dbg!(&thing);
             let _: Option<()> = try {
@@ -116,6 +169,22 @@ impl kameo::Actor for CoreActor {
             };
         }
 
+        self.this.get().tell(SyncPlaylists).await.unwrap();
+        if let Some(secs) = self.config.sync_interval {
+            let actor_ref = self.this.get().clone();
+            tokio::spawn(async move {
+                let period = Duration::from_secs(secs);
+                let mut interval =
+                    tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+                loop {
+                    interval.tick().await;
+                    if actor_ref.tell(SyncPlaylists).await.is_err() {
+                        break; // actor is gone
+                    }
+                }
+            });
+        }
+
         // for pl in self.config.playlists.iter() {
         //     if let Some(id) = &pl.id {
         //         dbg!(&id);
@@ -145,21 +214,241 @@ impl Message<Vec<MessageBundle>> for CoreActor {
         msg.retain(|v| !v.links.is_empty());
         tracing::info!("{} with links", msg.len());
 
-        for msg in msg {
-            for link in msg.links {
-                if link.service == types::Service::Spotify {
-                    // Really this should be batched
-                    if let Some(r) = &self.spotify {
-                        r.tell(FetchThing { id: link.id }).await.unwrap();
-                    } else {
-                        // TODO latching messages
-                        // tracing::info!("no_spotify")
-                    }
+        self.db.add_messages(&msg).await.log_and_drop::<Bug>();
+
+        let library = &self.config.library;
+        let to_save = msg
+            .iter()
+            .filter(|m| library.channels.contains(&m.channel_id))
+            .flat_map(|m| m.links.iter())
+            .filter(|l| l.service == types::Service::Spotify)
+            .filter(|l| match l.kind {
+                Some(types::Kind::Track | types::Kind::Album) => true,
+                Some(types::Kind::Artist) => library.follow_artists,
+                _ => false,
+            })
+            .map(|l| l.id.clone())
+            .collect::<Vec<_>>();
+
+        let ids = msg
+            .into_iter()
+            .flat_map(|m| m.links)
+            .filter(|l| l.service == types::Service::Spotify)
+            .map(|l| l.id)
+            .collect::<Vec<_>>();
+        if ids.is_empty() {
+            return;
+        }
+        if let Some(r) = &self.spotify {
+            // things already in the db get skipped
+            r.tell(FetchThings { ids }).await.unwrap();
+            if !to_save.is_empty() {
+                r.tell(SaveToLibrary {
+                    ids: to_save,
+                    follow: library.follow_artists,
+                })
+                .await
+                .unwrap();
+            }
+        } else {
+            // TODO latching messages
+            // tracing::info!("no_spotify")
+        }
+    }
+}
+
+impl CoreActor {
+    /// spotify id for a configured playlist, creating the playlist if the config asks for it
+    #[throws(eyre::Report)]
+    async fn playlist_id(&self, pl: &PlaylistConfig) -> Option<String> {
+        if let Some(id) = pl.resolve_id(&self.db.db).await? {
+            return Some(id);
+        }
+
+        let (true, Some(name), Some(spotify)) = (pl.create, &pl.name, &self.spotify) else {
+            return None;
+        };
+
+        let id = spotify::create_playlist(spotify, name.clone(), pl.desc.clone())
+            .await
+            .map_err(|e| e.wrap_err(format!("create playlist {}", name)))?;
+
+        playlist::store_playlist_id(&self.db.db, name, &id).await?;
+        Some(id)
+    }
+
+    /// spotify links we couldn't fetch, and why
+    #[throws(eyre::Report)]
+    async fn failed_fetches(&self, n: usize) -> String {
+        let failed = spotify::failed_fetches(&self.db.db, n).await?;
+        if failed.is_empty() {
+            return "no failed fetches".to_string();
+        }
+        failed
+            .iter()
+            .map(|f| {
+                format!(
+                    "{} ({} attempts, last {}): {}",
+                    f.uri,
+                    f.attempts,
+                    f.last_seen.format("%Y-%m-%d"),
+                    f.reason
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// see [`spotify::Module::rate_limit_state`]
+    #[throws(eyre::Report)]
+    async fn status(&self) -> String {
+        let spotify = self.spotify.as_ref().context("spotify is not enabled")?;
+        let state = spotify
+            .ask(RateLimitState)
+            .await
+            .map_err(|e| eyre::eyre!("rate limit state: {:?}", e))?;
+        state.to_string()
+    }
+
+    /// describe what a sync would do, without changing anything (including creating the playlist)
+    #[throws(eyre::Report)]
+    async fn dry_run(&self, pl: &PlaylistConfig) -> String {
+        let playlist = pl.build(&self.db.db).await?;
+        let Some(id) = pl.resolve_id(&self.db.db).await? else {
+            return format!(
+                "playlist does not exist, it would be created with {} tracks",
+                playlist.tracks.len()
+            );
+        };
+
+        let spotify = self.spotify.as_ref().context("spotify is not enabled")?;
+        let actions =
+            spotify::plan_playlist_sync(spotify, id, playlist.tracks, pl.no_delete).await?;
+
+        playlist::describe_plan(&self.db.db, &actions).await?
+    }
+}
+
+/// build every configured playlist and push it to spotify
+struct SyncPlaylists;
+
+impl Message<SyncPlaylists> for CoreActor {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        _msg: SyncPlaylists,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let Some(spotify) = &self.spotify else {
+            return;
+        };
+
+        for pl in self.config.playlists.iter() {
+            if self.cli.dry_run {
+                let plan = self.dry_run(pl).await;
+                println!("-------{}-------", pl.name.as_deref().unwrap_or_default());
+                match plan {
+                    Ok(plan) => println!("{}", plan),
+                    Err(e) => println!("{:?}", e),
                 }
+                continue;
             }
+
+            let id = match self.playlist_id(pl).await.log::<OnError>() {
+                Ok(Some(id)) => id,
+                Ok(None) => {
+                    tracing::warn!(name = ?pl.name, "playlist has no id, skipping sync");
+                    continue;
+                }
+                Err(_) => continue,
+            };
+
+            let Ok(playlist) = pl.build(&self.db.db).await.log::<OnError>() else {
+                continue;
+            };
+
+            spotify
+                .tell(SyncPlaylist {
+                    id,
+                    tracks: playlist.tracks,
+                    no_delete: pl.no_delete,
+                })
+                .await
+                .unwrap();
         }
     }
 }
+
+impl Message<ChatCommand> for CoreActor {
+    type Reply = ();
+
+    async fn handle(
+        &mut self,
+        msg: ChatCommand,
+        _ctx: kameo::message::Context<'_, Self, Self::Reply>,
+    ) -> Self::Reply {
+        let args = msg.text.split_ascii_whitespace();
+        let reply = match ChatCommands::try_parse_from(args) {
+            Ok(ChatCommands::DryRun { playlist }) => {
+                let name = playlist.join(" ").to_lowercase();
+                match self
+                    .config
+                    .playlists
+                    .iter()
+                    .find(|pl| pl.name.as_ref().map(|s| s.to_lowercase()) == Some(name.clone()))
+                {
+                    Some(pl) => match self.dry_run(pl).await {
+                        Ok(plan) => plan,
+                        Err(e) => e.to_string(),
+                    },
+                    None => format!("no playlist named {}", name),
+                }
+            }
+            Ok(ChatCommands::Failed { n }) => match self.failed_fetches(n).await {
+                Ok(s) => s,
+                Err(e) => e.to_string(),
+            },
+            Ok(ChatCommands::Status) => match self.status().await {
+                Ok(s) => s,
+                Err(e) => e.to_string(),
+            },
+            // `!` starts plenty of messages which aren't meant for us
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::InvalidSubcommand
+                        | ErrorKind::UnknownArgument
+                        | ErrorKind::DisplayHelpOnMissingArgumentOrSubcommand
+                ) =>
+            {
+                return;
+            }
+            Err(e) => e.render().to_string(),
+        };
+
+        match (msg.service, &self.discord) {
+            (types::Service::Discord, Some(discord)) => {
+                let channel_id = match ChannelId::from_str(&msg.channel_id) {
+                    Ok(c) => c,
+                    Err(e) => {
+                        tracing::error!("can't reply to channel {}: {}", msg.channel_id, e);
+                        return;
+                    }
+                };
+                discord
+                    .tell(Say {
+                        channel_id,
+                        text: reply,
+                    })
+                    .await
+                    .unwrap();
+            }
+            _ => tracing::info!("{}", reply),
+        }
+    }
+}
+
 fn print_completions<G: clap_complete::Generator>(gen: G, cmd: &mut clap::Command) {
     generate(gen, cmd, cmd.get_name().to_string(), &mut std::io::stdout());
 }
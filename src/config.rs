@@ -11,6 +11,14 @@ use serde::{Deserialize, Serialize};
 pub struct AppConfig {
     #[serde(default)]
     pub playlists: Vec<PlaylistConfig>,
+
+    /// saving shared links to the spotify account's library
+    #[serde(default)]
+    pub library: LibraryConfig,
+
+    /// seconds between playlist syncs, playlists are always synced once at startup
+    pub sync_interval: Option<u64>,
+
     pub database: crate::database::Config,
 
     pub spotify: ModuleConfig<crate::service::spotify::Config>,
@@ -47,6 +55,48 @@ pub struct PlaylistConfig {
 
     #[serde(default)]
     pub create: bool,
+
+    /// only ever prepend new tracks, so tracks added by hand are never removed
+    #[serde(default)]
+    pub no_delete: bool,
+
+    /// channels to pull links from, empty means all of them
+    #[serde(default)]
+    pub inputs: Vec<crate::playlist::PlaylistInput>,
+
+    #[serde(default = "default_true")]
+    pub no_repeat: bool,
+    #[serde(default)]
+    pub shuffle: bool,
+    #[serde(default)]
+    pub reverse: bool,
+
+    /// which kinds of links get expanded into the playlist
+    /// artists need `artist_expand` in the spotify config
+    #[serde(default = "default_kind")]
+    pub kind: Vec<crate::types::Kind>,
+    //filter: Vec<Filter>, TODO (reacts!)
+}
+
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct LibraryConfig {
+    /// chat channel ids, as stored in the `channel` table, whose shared tracks and albums
+    /// are saved to the library. none by default
+    #[serde(default)]
+    pub channels: Vec<String>,
+
+    /// also follow shared artists, and the artists of what is saved
+    #[serde(default = "default_true")]
+    pub follow_artists: bool,
+}
+
+//NOTE: Must be function because https://github.com/serde-rs/serde/issues/2254
+fn default_true() -> bool {
+    true
+}
+
+fn default_kind() -> Vec<crate::types::Kind> {
+    vec![crate::types::Kind::Track, crate::types::Kind::Album]
 }
 
 #[derive(Debug, Clone, clap::Parser)]
@@ -12,7 +12,10 @@ use surrealdb::{
 };
 use tracing::info;
 
-use crate::types::Link;
+use crate::types::{
+    chat::{Message, MessageBundle},
+    Link, Service,
+};
 
 static DATABASE: OnceLock<Database> = OnceLock::new();
 pub type MyDb = Arc<Surreal<Any>>;
@@ -143,4 +146,54 @@ impl Database {
             .bind(("msg", msg))
             .await?;
     }
+
+    /// store chat messages which have links, and relate them to the linked things.
+    /// the old edges go first, so rescanning (or an edit) doesn't leave duplicates.
+    #[throws]
+    pub async fn add_messages(&self, msgs: &[MessageBundle]) {
+        let query = r#"
+            UPSERT $id MERGE $bundle;
+            DELETE link WHERE in = $id;
+            RELATE $id->link->$targets;
+        "#;
+
+        #[derive(Debug, Serialize, Deserialize)]
+        struct MsgBundle {
+            service: Service,
+            message: Message,
+            user: RecordId,
+            channel: RecordId,
+            link: Vec<Link>,
+        }
+
+        for msg in msgs.iter().filter(|m| !m.links.is_empty()) {
+            let bundle = MsgBundle {
+                service: msg.service,
+                message: Message {
+                    text: msg.content.clone(),
+                    timestamp: msg.timestamp,
+                },
+                user: chat_key("user", &msg.user_id),
+                channel: chat_key("channel", &msg.channel_id),
+                link: msg.links.clone(),
+            };
+
+            self.db
+                .query(query)
+                .bind(("id", chat_key("message", &msg.id)))
+                .bind(("bundle", bundle))
+                .bind(("targets", msg.links.iter().map(Link::to_thing).collect::<Vec<_>>()))
+                .await?
+                .check()?;
+        }
+    }
+}
+
+/// chat ids as record ids. discord's are numbers, and the discord module keys them as such
+/// (`ToSurreal` in its convert.rs), so both have to agree.
+fn chat_key(table: &str, id: &str) -> RecordId {
+    match id.parse::<i64>() {
+        Ok(n) => RecordId::from((table, n)),
+        Err(_) => RecordId::from((table, id)),
+    }
 }
@@ -8,7 +8,7 @@ pub mod database;
 //pub use database::types;
 
 pub mod config;
-//pub mod playlist;
+pub mod playlist;
 //pub mod traits;
 pub mod types;
 
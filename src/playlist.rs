@@ -1,52 +1,563 @@
-use crate::{
-    database::Database,
-    types::{ChannelId, Kind, Song},
+use std::{
+    collections::{HashMap, HashSet},
+    hash::{DefaultHasher, Hash, Hasher},
 };
-use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-struct PlaylistConfig {
-    name: String,
-    inputs: Vec<PlaylistInput>,
+use itertools::Itertools;
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
+use rspotify::model::{Id, PlayableId, TrackId};
+use surrealdb::RecordId;
 
-    no_repeat: bool,
-    shuffle: bool,
-    reverse: bool,
+use crate::{
+    config::PlaylistConfig,
+    prelude::*,
+    types::{Kind, Link, Service},
+    utils::{diff::Actions, when_even::OnError},
+};
 
-    kind: Vec<Kind>,
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum PlaylistInput {
+    /// chat channel id, as stored in the `channel` table
+    Channel(String),
+}
 
-    //filter: Vec<Filter>, TODO (reacts!)
-    description: String,
+#[derive(Debug, Clone)]
+pub struct Playlist {
+    pub config: PlaylistConfig,
+    pub tracks: Vec<TrackId<'static>>, //TODO metatrack
+    pub date: DateTime<Utc>,           //XXX should be monotonic?
 }
 
-impl Default for PlaylistConfig {
-    fn default() -> Self {
-        Self {
-            name: "".to_string(),
-            inputs: vec![],
-            no_repeat: true,
-            shuffle: false,
-            reverse: false,
-            kind: vec![Kind::Track, Kind::Album],
-            description: "".to_string(),
+impl PlaylistConfig {
+    /// spotify id of the playlist, either from config or from when we created it
+    #[throws(eyre::Report)]
+    pub async fn resolve_id(&self, db: &MyDb) -> Option<String> {
+        match (&self.id, &self.name) {
+            (Some(id), _) => Some(id.clone()),
+            (None, Some(name)) => lookup_playlist_id(db, name).await?,
+            (None, None) => None,
+        }
+    }
+
+    /// Build the tracklist from links posted in chat.
+    /// Albums and artists are expanded into their tracks, so they need to have been fetched into the db first.
+    #[throws(eyre::Report)]
+    #[tracing::instrument(skip_all, fields(name = ?self.name))]
+    pub async fn build(&self, db: &MyDb) -> Playlist {
+        let channels = self
+            .inputs
+            .iter()
+            .map(|i| match i {
+                PlaylistInput::Channel(c) => c.clone(),
+            })
+            .collect_vec();
+
+        let query = r#"
+            SELECT message.timestamp AS ts, link FROM message
+            WHERE array::len(link) > 0
+                AND (array::len($channels) = 0 OR <string> record::id(channel) IN $channels)
+            ORDER BY ts ASC
+        "#;
+
+        #[derive(Debug, Deserialize)]
+        struct Row {
+            #[serde(default)]
+            link: Vec<Link>,
+        }
+
+        let rows: Vec<Row> = db.query(query).bind(("channels", channels)).await?.take(0)?;
+
+        let links = rows
+            .into_iter()
+            .flat_map(|r| r.link)
+            .filter(|l| l.service == Service::Spotify)
+            .filter(|l| l.kind.is_some_and(|k| self.kind.contains(&k)))
+            .collect_vec();
+
+        let albums = album_tracks(
+            db,
+            links
+                .iter()
+                .filter(|l| l.kind == Some(Kind::Album))
+                .unique_by(|l| &l.id)
+                .map(|l| l.to_thing())
+                .collect(),
+        )
+        .await?;
+
+        let artists = artist_tracks(
+            db,
+            links
+                .iter()
+                .filter(|l| l.kind == Some(Kind::Artist))
+                .unique_by(|l| &l.id)
+                .map(|l| l.to_thing())
+                .collect(),
+        )
+        .await?;
+
+        let mut tracks = Vec::new();
+        for link in links.iter() {
+            match link.kind {
+                Some(Kind::Track) => tracks.push(link.id.clone()),
+                Some(Kind::Album) => match albums.get(&link.id) {
+                    Some(v) => tracks.extend(v.iter().cloned()),
+                    None => tracing::debug!(id = link.id, "album not fetched yet"),
+                },
+                Some(Kind::Artist) => match artists.get(&link.id) {
+                    Some(v) => tracks.extend(v.iter().cloned()),
+                    None => tracing::debug!(id = link.id, "artist not expanded yet"),
+                },
+                k => tracing::debug!(id = link.id, "cannot expand {:?} into tracks", k),
+            }
+        }
+
+        let tracks = tracks
+            .into_iter()
+            .filter_map(|id| {
+                TrackId::from_id_or_uri(&id)
+                    .log::<OnError>()
+                    .ok()
+                    .map(|t| t.clone_static())
+            })
+            .collect_vec();
+        let mut tracks = playable(db, tracks).await?;
+
+        if self.no_repeat {
+            // keep the first time a track was shared
+            let mut seen = HashSet::new();
+            tracks.retain(|t| seen.insert(t.clone()));
+        }
+
+        if self.shuffle {
+            // seeded by name, so that rebuilding an unchanged playlist gives the same order
+            let mut hasher = DefaultHasher::new();
+            self.name.hash(&mut hasher);
+            tracks.shuffle(&mut StdRng::seed_from_u64(hasher.finish()));
+        }
+
+        if self.reverse {
+            tracks.reverse();
+        }
+
+        tracing::info!(n = tracks.len(), links = links.len(), "built playlist");
+
+        Playlist {
+            config: self.clone(),
+            tracks,
+            date: Utc::now(),
         }
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
-enum PlaylistInput {
-    Channel(ChannelId),
+/// album uri -> ordered track uris, for albums which are in the db
+#[throws(eyre::Report)]
+async fn album_tracks(db: &MyDb, albums: Vec<RecordId>) -> HashMap<String, Vec<String>> {
+    let query = r#"
+        SELECT record::id(out) AS album, record::id(in) AS track,
+            in.disc_number AS disc, in.track_number AS number
+        FROM on WHERE out IN $albums
+    "#;
+
+    #[derive(Debug, Deserialize)]
+    struct Row {
+        album: String,
+        track: String,
+        disc: i32,
+        number: u32,
+    }
+
+    let rows: Vec<Row> = db.query(query).bind(("albums", albums)).await?.take(0)?;
+    rows.into_iter()
+        .sorted_by_key(|r| (r.disc, r.number))
+        .map(|r| (r.album, r.track))
+        .into_group_map()
 }
 
-pub struct Playlist {
-    pub config: PlaylistConfig,
-    pub tracks: Vec<Song>,   //TODO metatrack
-    pub date: DateTime<Utc>, //XXX should be monotonic?
+/// artist uri -> track uris, from the top tracks or else the latest release,
+/// whichever the spotify module fetched for it
+#[throws(eyre::Report)]
+async fn artist_tracks(db: &MyDb, artists: Vec<RecordId>) -> HashMap<String, Vec<String>> {
+    if artists.is_empty() {
+        return HashMap::new();
+    }
+
+    let query = "SELECT record::id(id) AS id, top_tracks, latest_release FROM $artists";
+
+    #[derive(Debug, Deserialize)]
+    struct Row {
+        id: String,
+        top_tracks: Option<Vec<String>>,
+        latest_release: Option<String>,
+    }
+
+    let rows: Vec<Row> = db.query(query).bind(("artists", artists)).await?.take(0)?;
+
+    let releases = rows
+        .iter()
+        .filter_map(|r| r.latest_release.as_ref())
+        .map(|a| RecordId::from_table_key("album", a))
+        .collect_vec();
+    let releases = album_tracks(db, releases).await?;
+
+    rows.into_iter()
+        .filter_map(|r| {
+            let tracks = match (r.top_tracks, r.latest_release) {
+                (Some(top), _) if !top.is_empty() => top,
+                (_, Some(album)) => releases.get(&album)?.clone(),
+                _ => return None,
+            };
+            Some((r.id, tracks))
+        })
+        .collect()
 }
 
-impl PlaylistConfig {
-    fn build(db: &Database) -> Playlist {
-        db.db.query("SELECT links FROM message ORDER BY date ASC");
+/// Tracks spotify relinked in our market become the version which plays here,
+/// ones which don't play here at all are left out. Tracks the db knows nothing about are kept.
+#[throws(eyre::Report)]
+pub async fn playable(db: &MyDb, tracks: Vec<TrackId<'static>>) -> Vec<TrackId<'static>> {
+    let ids = tracks
+        .iter()
+        .unique()
+        .map(|t| RecordId::from_table_key("track", t.uri()))
+        .collect_vec();
+    let query = "SELECT record::id(id) AS id, is_playable, relinked FROM $tracks";
+
+    #[derive(Debug, Deserialize)]
+    struct Row {
+        id: String,
+        is_playable: Option<bool>,
+        relinked: Option<String>,
+    }
+
+    let rows: Vec<Row> = db.query(query).bind(("tracks", ids)).await?.take(0)?;
+    let rows: HashMap<String, Row> = rows.into_iter().map(|r| (r.id.clone(), r)).collect();
+
+    tracks
+        .into_iter()
+        .filter_map(|t| {
+            let Some(row) = rows.get(&t.uri()) else {
+                return Some(t);
+            };
+            if row.is_playable == Some(false) {
+                tracing::debug!(id = %t.uri(), "not playable in our market");
+                return None;
+            }
+            match &row.relinked {
+                Some(r) => TrackId::from_id_or_uri(r)
+                    .log::<OnError>()
+                    .ok()
+                    .map(|r| r.clone_static()),
+                None => Some(t),
+            }
+        })
+        .collect()
+}
+
+const CREATED_PLAYLIST: &str = "created_playlist";
+
+/// playlists which were created by the bot, keyed by (lowercase) name
+#[derive(Debug, Serialize, Deserialize)]
+struct CreatedPlaylist {
+    name: String,
+    id: String,
+    date: DateTime<Utc>,
+}
+
+/// id of the playlist we created for this name, if any
+#[throws(eyre::Report)]
+pub async fn lookup_playlist_id(db: &MyDb, name: &str) -> Option<String> {
+    let r: Option<CreatedPlaylist> = db.select((CREATED_PLAYLIST, name.to_lowercase())).await?;
+    r.map(|r| r.id)
+}
+
+#[throws(eyre::Report)]
+pub async fn store_playlist_id(db: &MyDb, name: &str, id: &str) {
+    let bundle = CreatedPlaylist {
+        name: name.to_string(),
+        id: id.to_string(),
+        date: Utc::now(),
+    };
+    let _: Option<CreatedPlaylist> = db
+        .upsert((CREATED_PLAYLIST, name.to_lowercase()))
+        .content(bundle)
+        .await?;
+}
+
+/// Human readable version of a sync plan, with track names from the db where we have them.
+/// Anything else (episodes) is shown by uri.
+#[throws(eyre::Report)]
+pub async fn describe_plan(db: &MyDb, actions: &[Actions<PlayableId<'static>>]) -> String {
+    if actions.is_empty() {
+        return "playlist is already correct".to_string();
+    }
+
+    let ids = actions
+        .iter()
+        .flat_map(|a| match a {
+            Actions::Append(v)
+            | Actions::Add(v, _)
+            | Actions::DeleteAll(v)
+            | Actions::Replace(v) => v.iter().collect_vec(),
+            Actions::Delete(v) => v.iter().map(|(_, t)| t).collect_vec(),
+            Actions::Move { .. } => vec![],
+        })
+        .unique()
+        .map(|t| RecordId::from_table_key("track", t.uri()))
+        .collect_vec();
+
+    #[derive(Debug, Deserialize)]
+    struct Row {
+        id: String,
+        name: String,
+    }
+
+    let query = "SELECT record::id(id) AS id, name FROM $tracks";
+    let rows: Vec<Row> = db.query(query).bind(("tracks", ids)).await?.take(0)?;
+    let names: HashMap<String, String> = rows.into_iter().map(|r| (r.id, r.name)).collect();
+    let name = |t: &PlayableId<'static>| names.get(&t.uri()).cloned().unwrap_or_else(|| t.uri());
+
+    let mut out = Vec::new();
+    for a in actions {
+        match a {
+            Actions::Replace(v) => {
+                out.push(format!("replace everything with {} tracks", v.len()));
+                out.extend(v.iter().map(|t| format!("  + {}", name(t))));
+            }
+            Actions::Append(v) => {
+                out.push(format!("append {} tracks", v.len()));
+                out.extend(v.iter().map(|t| format!("  + {}", name(t))));
+            }
+            Actions::Add(v, i) => {
+                out.push(format!("insert {} tracks at #{}", v.len(), i));
+                out.extend(v.iter().map(|t| format!("  + {}", name(t))));
+            }
+            Actions::Delete(v) => {
+                out.push(format!("delete {} tracks", v.len()));
+                out.extend(v.iter().map(|(i, t)| format!("  - #{} {}", i, name(t))));
+            }
+            Actions::DeleteAll(v) => {
+                out.push(format!("delete every copy of {} tracks", v.len()));
+                out.extend(v.iter().map(|t| format!("  - {}", name(t))));
+            }
+            Actions::Move {
+                range_start,
+                range_length,
+                insert_before,
+            } => out.push(format!(
+                "move {} tracks from #{} to before #{}",
+                range_length, range_start, insert_before
+            )),
+        }
+    }
+    out.join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use rspotify::model::{Id, PlayableId, TrackId};
+    use serde_json::json;
+
+    use crate::{
+        config::PlaylistConfig,
+        database::Database,
+        prelude::*,
+        types::{chat::MessageBundle, Service},
+        utils::{diff::Actions, links::extract_links},
+    };
+
+    use super::{describe_plan, playable};
+
+    async fn mem_db() -> MyDb {
+        let db: MyDb = surrealdb::engine::any::connect("mem://")
+            .await
+            .unwrap()
+            .into();
+        db.use_ns("test").use_db("test").await.unwrap();
+        db
+    }
+
+    /// share these links in chat, one message each, oldest first
+    async fn share(db: &MyDb, urls: &[&str]) {
+        let database = Database {
+            db: db.clone(),
+            links: Default::default(),
+        };
+        let msgs = urls
+            .iter()
+            .enumerate()
+            .map(|(i, url)| MessageBundle {
+                service: Service::Discord,
+                id: (i + 1).to_string(),
+                timestamp: Utc::now() + chrono::Duration::seconds(i as i64),
+                content: url.to_string(),
+                links: extract_links(url),
+                username: "someone".to_string(),
+                user_id: "1".to_string(),
+                channel_id: "1".to_string(),
+            })
+            .collect::<Vec<_>>();
+        database.add_messages(&msgs).await.unwrap();
+    }
+
+    /// an album of `(track, disc, number)`s, like the spotify module stores it
+    async fn add_album(db: &MyDb, album: &str, tracks: &[(&str, i32, u32)]) {
+        let query = "
+            UPSERT $track SET name = $name, disc_number = $disc, track_number = $number;
+            RELATE $track->on->$album;
+        ";
+        for (id, disc, number) in tracks {
+            db.query(query)
+                .bind(("track", thing("track", id)))
+                .bind(("name", format!("track {}", id)))
+                .bind(("disc", *disc))
+                .bind(("number", *number))
+                .bind(("album", thing("album", album)))
+                .await
+                .unwrap()
+                .check()
+                .unwrap();
+        }
+    }
+
+    /// records are keyed by uri
+    fn thing(table: &str, id: &str) -> surrealdb::RecordId {
+        surrealdb::RecordId::from_table_key(table, format!("spotify:{}:{}", table, id))
+    }
+
+    fn config(options: serde_json::Value) -> PlaylistConfig {
+        let mut config = json!({ "name": "test" });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(options.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    async fn build(db: &MyDb, options: serde_json::Value) -> Vec<String> {
+        let pl = config(options).build(db).await.unwrap();
+        pl.tracks.iter().map(|t| t.id().to_string()).collect()
+    }
+
+    #[tokio::test]
+    async fn album_expansion_order() {
+        let db = mem_db().await;
+        let album = [("d2n1", 2, 1), ("d1n2", 1, 2), ("d1n1", 1, 1)];
+        add_album(&db, "album", &album).await;
+        share(&db, &["https://open.spotify.com/album/album"]).await;
+        assert_eq!(build(&db, json!({})).await, ["d1n1", "d1n2", "d2n1"]);
+    }
+
+    #[tokio::test]
+    async fn no_repeat() {
+        let db = mem_db().await;
+        add_album(&db, "album", &[("a", 1, 1), ("b", 1, 2)]).await;
+        share(
+            &db,
+            &[
+                "https://open.spotify.com/track/b",
+                "https://open.spotify.com/album/album",
+                "https://open.spotify.com/track/a",
+            ],
+        )
+        .await;
+        // the first time each was shared
+        assert_eq!(build(&db, json!({})).await, ["b", "a"]);
+        assert_eq!(
+            build(&db, json!({ "no_repeat": false })).await,
+            ["b", "a", "b", "a"]
+        );
+    }
+
+    #[tokio::test]
+    async fn seeded_shuffle_and_reverse() {
+        let db = mem_db().await;
+        let tracks = (0..20).map(|i| format!("t{}", i)).collect::<Vec<_>>();
+        let album = tracks
+            .iter()
+            .enumerate()
+            .map(|(i, t)| (t.as_str(), 1, i as u32 + 1))
+            .collect::<Vec<_>>();
+        add_album(&db, "album", &album).await;
+        share(&db, &["https://open.spotify.com/album/album"]).await;
+
+        let plain = build(&db, json!({})).await;
+        assert_eq!(plain, tracks);
+
+        let shuffled = build(&db, json!({ "shuffle": true })).await;
+        assert_ne!(shuffled, plain);
+        assert_eq!(build(&db, json!({ "shuffle": true })).await, shuffled);
+        let mut sorted = shuffled.clone();
+        sorted.sort();
+        let mut expected = plain.clone();
+        expected.sort();
+        assert_eq!(sorted, expected);
+
+        let mut reversed = plain.clone();
+        reversed.reverse();
+        assert_eq!(build(&db, json!({ "reverse": true })).await, reversed);
+    }
+
+    #[tokio::test]
+    async fn playable_filters_and_relinks() {
+        let db = mem_db().await;
+        let query = "
+            UPSERT $gone SET is_playable = false;
+            UPSERT $moved SET is_playable = true, relinked = 'spotify:track:here';
+            UPSERT $fine SET is_playable = true;
+        ";
+        db.query(query)
+            .bind(("gone", thing("track", "gone")))
+            .bind(("moved", thing("track", "moved")))
+            .bind(("fine", thing("track", "fine")))
+            .await
+            .unwrap()
+            .check()
+            .unwrap();
+
+        let ids = |ids: &[&str]| {
+            ids.iter()
+                .map(|id| TrackId::from_id(*id).unwrap().clone_static())
+                .collect::<Vec<_>>()
+        };
+        // tracks the db doesn't know are kept as they are
+        let got = playable(&db, ids(&["fine", "gone", "moved", "unknown"]))
+            .await
+            .unwrap();
+        assert_eq!(got, ids(&["fine", "here", "unknown"]));
+    }
+
+    #[tokio::test]
+    async fn describe() {
+        let db = mem_db().await;
+        add_album(&db, "album", &[("known", 1, 1)]).await;
+        assert_eq!(
+            describe_plan(&db, &[]).await.unwrap(),
+            "playlist is already correct"
+        );
+
+        let id = |id: &str| PlayableId::Track(TrackId::from_id(id).unwrap().clone_static());
+        let plan = [
+            Actions::Append(vec![id("known"), id("unknown")]),
+            Actions::Delete(vec![(3, id("known"))]),
+            Actions::Move {
+                range_start: 0,
+                range_length: 1,
+                insert_before: 2,
+            },
+        ];
+        assert_eq!(
+            describe_plan(&db, &plan).await.unwrap(),
+            [
+                "append 2 tracks",
+                "  + track known",
+                "  + spotify:track:unknown",
+                "delete 1 tracks",
+                "  - #3 track known",
+                "move 1 tracks from #0 to before #2",
+            ]
+            .join("\n")
+        );
     }
 }
@@ -16,6 +16,8 @@ use serenity::{
 use tracing::info;
 
 pub use crate::prelude::*;
+use crate::types::{chat::ChatCommand, Service};
+use crate::utils::pubsub::PUBSUB;
 use crate::utils::when_even::{Bug, Loggable};
 
 // honestly not sure what I should return here, a Client? an Http?, Context?
@@ -79,6 +81,13 @@ impl EventHandler for Handler {
             if let Err(why) = msg.channel_id.say(&ctx.http, "Pong!").await {
                 println!("Error sending message: {:?}", why);
             }
+        } else if let Some(text) = msg.content.strip_prefix('!') {
+            let cmd = ChatCommand {
+                service: Service::Discord,
+                channel_id: msg.channel_id.to_string(),
+                text: text.to_string(),
+            };
+            PUBSUB.publish(cmd).await.unwrap();
         }
     }
 
@@ -71,6 +71,23 @@ impl Module {
         PUBSUB.publish(msgs).await.unwrap();
     }
 
+    /// send a message, cut down to fit in discord's limit
+    #[message]
+    pub async fn say(&self, channel_id: ChannelId, text: String) {
+        const MAX_LEN: usize = 2000;
+
+        let mut text = text;
+        if text.chars().count() > MAX_LEN {
+            text = text.chars().take(MAX_LEN - 3).collect::<String>() + "...";
+        }
+
+        let client = self.client.get().unwrap();
+        channel_id
+            .say(&client.http, text)
+            .await
+            .log_and_drop::<Bug>();
+    }
+
     #[message]
     pub async fn scan_since(&self, channel_id: ChannelId) {
         //TODO will need some kind of busy flag
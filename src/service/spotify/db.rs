@@ -1,101 +1,614 @@
+use std::collections::HashSet;
+
 use eyre::ContextCompat;
+use itertools::Itertools;
+use rspotify::model::{
+    AlbumType, DatePrecision, FullAlbum, FullArtist, FullPlaylist, FullTrack, PlayableItem,
+    PublicUser, SimplifiedAlbum, SimplifiedArtist, SimplifiedTrack, TrackId, TrackLink,
+};
 use surrealdb::RecordId;
 use tracing::instrument;
 
-use crate::prelude::*;
+use crate::{prelude::*, utils::when_even::OnError};
+
+use super::{ArtistExpansion, Priority, SpotifyThing};
+
+/// Write everything the fetcher got back to the db, returns the uris which couldn't be written
+pub async fn add_things(db: &MyDb, data: Vec<SpotifyThing>) -> HashSet<String> {
+    let uris = data.iter().filter_map(SpotifyThing::uri).collect_vec();
+
+    let mut unstored = HashSet::new();
+    for d in data {
+        let uri = d.uri();
+        // errors are logged by instrument
+        let ret = match d {
+            SpotifyThing::Album(a) => add_full_album(db, a).await,
+            SpotifyThing::Track(t) => add_full_track(db, t).await,
+            SpotifyThing::Artist(a, extra) => add_full_artist(db, a, extra).await,
+            SpotifyThing::Playlist(p) => add_full_playlist(db, p).await,
+            SpotifyThing::User(u, playlists) => add_user(db, u, playlists).await,
+        };
+        if ret.is_err() {
+            unstored.extend(uri);
+        }
+    }
+
+    // what didn't make it keeps its failure history, it will be fetched again
+    let stored = uris.into_iter().filter(|u| !unstored.contains(u)).collect();
+    clear_failed(db, stored).await.log_and_drop::<OnError>();
+    unstored
+}
+
+const FETCH_ERROR: &str = "fetch_error";
 
+/// An id spotify wouldn't give us, keyed by uri
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FetchError {
+    pub uri: String,
+    pub reason: String,
+    pub attempts: u32,
+    pub first_seen: DateTime<Utc>,
+    pub last_seen: DateTime<Utc>,
+    /// don't ask again before this, doubles with every attempt
+    pub retry_after: DateTime<Utc>,
+}
+
+/// Record ids which spotify didn't return, with why
 #[throws(eyre::Report)]
-#[instrument(err, skip(db))]
-pub async fn add_full_album(db: &MyDb, album: rspotify::model::FullAlbum) {
-    let album_id = RecordId::from_table_key("album", &album.id.to_string());
+pub async fn record_failed(db: &MyDb, failed: Vec<(String, String)>) {
+    const MAX_BACKOFF_HOURS: i64 = 24 * 30;
 
-    #[derive(Debug, Deserialize, Serialize)]
-    struct ArtistBundle {
-        id: RecordId,
-        name: String,
+    let now = Utc::now();
+    for (uri, reason) in failed {
+        tracing::warn!(uri = %uri, reason = %reason, "fetch failed");
+        let old: Option<FetchError> = db.select((FETCH_ERROR, &uri)).await?;
+        let attempts = old.as_ref().map_or(1, |o| o.attempts + 1);
+        let backoff = 2i64.saturating_pow(attempts - 1).min(MAX_BACKOFF_HOURS);
+        let bundle = FetchError {
+            first_seen: old.map_or(now, |o| o.first_seen),
+            uri: uri.clone(),
+            reason,
+            attempts,
+            last_seen: now,
+            retry_after: now + chrono::Duration::hours(backoff),
+        };
+        let _res: Option<FetchError> = db.upsert((FETCH_ERROR, &uri)).content(bundle).await?;
     }
+}
 
-    let artist_bundle: Vec<ArtistBundle> = album
-        .artists
+#[throws(eyre::Report)]
+async fn clear_failed(db: &MyDb, uris: Vec<String>) {
+    if uris.is_empty() {
+        return;
+    }
+    let ids = uris
         .iter()
-        .filter_map(|a| {
-            Some(ArtistBundle {
-                id: RecordId::from(("artist", &a.id.clone()?.to_string())),
-                name: a.name.clone(),
-            })
-        })
-        .collect();
-    let artists = artist_bundle.iter().map(|b| b.id.clone()).collect();
+        .map(|uri| RecordId::from((FETCH_ERROR, uri)))
+        .collect_vec();
+    db.query("DELETE $ids").bind(("ids", ids)).await?.check()?;
+}
 
-    #[derive(Debug, Deserialize, Serialize)]
-    struct TrackBundle {
-        id: RecordId,
-        name: String,
-        album: RecordId,
+const PENDING_FETCH: &str = "pending_fetch";
+
+/// A uri which is queued or in flight, keyed by uri.
+/// It stays until what spotify returned is in the db, so a restart picks it up again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PendingFetch {
+    uri: String,
+    priority: Priority,
+    queued: DateTime<Utc>,
+}
+
+/// Already pending uris keep the higher priority and when they were first queued.
+/// One query, so it is done before anything can clear them again.
+#[throws(eyre::Report)]
+pub async fn add_pending(db: &MyDb, uris: Vec<String>, priority: Priority) {
+    if uris.is_empty() {
+        return;
+    }
+    // what a uri can already be pending at, which is kept
+    let higher = [Priority::Bulk, Priority::Interactive]
+        .into_iter()
+        .filter(|p| *p > priority)
+        .collect_vec();
+    let query = "
+        FOR $uri IN $uris {
+            LET $id = type::thing($table, $uri);
+            UPSERT $id CONTENT {
+                uri: $uri,
+                priority: IF $id.priority IN $higher THEN $id.priority ELSE $priority END,
+                queued: $id.queued ?? $now,
+            };
+        };
+    ";
+    db.query(query)
+        .bind(("table", PENDING_FETCH))
+        .bind(("uris", uris))
+        .bind(("priority", priority))
+        .bind(("higher", higher))
+        .bind(("now", Utc::now()))
+        .await?
+        .check()?;
+}
+
+#[throws(eyre::Report)]
+pub async fn clear_pending(db: &MyDb, uris: Vec<String>) {
+    if uris.is_empty() {
+        return;
+    }
+    let ids = uris
+        .iter()
+        .map(|uri| RecordId::from((PENDING_FETCH, uri)))
+        .collect_vec();
+    db.query("DELETE $ids").bind(("ids", ids)).await?.check()?;
+}
+
+/// Everything still pending, oldest first
+#[throws(eyre::Report)]
+pub async fn pending(db: &MyDb) -> Vec<(String, Priority)> {
+    let rows: Vec<PendingFetch> = db.select(PENDING_FETCH).await?;
+    rows.into_iter()
+        .sorted_by_key(|r| r.queued)
+        .map(|r| (r.uri, r.priority))
+        .collect()
+}
+
+const SAVED: &str = "saved";
+
+/// Of these uris, the ones which haven't been saved to the library (or followed) yet
+#[throws(eyre::Report)]
+pub async fn unsaved(db: &MyDb, uris: Vec<String>) -> Vec<String> {
+    let ids = uris
+        .iter()
+        .map(|uri| RecordId::from((SAVED, uri)))
+        .collect_vec();
+    let query = "SELECT VALUE record::id(id) FROM $ids";
+    let saved: Vec<String> = db.query(query).bind(("ids", ids)).await?.take(0)?;
+    let saved: HashSet<String> = saved.into_iter().collect();
+    uris.into_iter()
+        .unique()
+        .filter(|uri| !saved.contains(uri))
+        .collect()
+}
+
+#[throws(eyre::Report)]
+pub async fn mark_saved(db: &MyDb, uris: Vec<String>) {
+    #[derive(Debug, Serialize, Deserialize)]
+    struct Saved {
+        date: DateTime<Utc>,
     }
 
-    let track_bundle: Vec<TrackBundle> = album
-        .tracks
-        .items
+    for uri in uris {
+        let _res: Option<Saved> = db
+            .upsert((SAVED, &uri))
+            .content(Saved { date: Utc::now() })
+            .await?;
+    }
+}
+
+/// Of these ids, the ones which failed recently enough that we shouldn't ask again yet
+#[throws(eyre::Report)]
+pub async fn backing_off(db: &MyDb, uris: &[String]) -> HashSet<String> {
+    let ids = uris
         .iter()
-        .filter_map(|a| {
-            Some(TrackBundle {
-                id: RecordId::from(("track", &a.id.clone()?.to_string())),
-                name: a.name.clone(),
-                album: album_id.clone(),
+        .map(|uri| RecordId::from((FETCH_ERROR, uri)))
+        .collect_vec();
+    let rows: Vec<FetchError> = db
+        .query("SELECT * OMIT id FROM $ids")
+        .bind(("ids", ids))
+        .await?
+        .take(0)?;
+
+    let now = Utc::now();
+    rows.into_iter()
+        .filter(|r| r.retry_after > now)
+        .map(|r| r.uri)
+        .collect()
+}
+
+/// The most recent failures, newest first
+#[throws(eyre::Report)]
+pub async fn failed_fetches(db: &MyDb, limit: usize) -> Vec<FetchError> {
+    db.query("SELECT * OMIT id FROM type::table($table) ORDER BY last_seen DESC LIMIT $limit")
+        .bind(("table", FETCH_ERROR))
+        .bind(("limit", limit))
+        .await?
+        .take(0)?
+}
+
+// Schema:
+// `artist`, `album` and `track` hold the useful fields from spotify (ids are uris).
+// `track->on->album`, `track->by->artist` and `album->by->artist` are graph edges,
+// which are replaced whenever the record is written.
+// Fields only in some responses (eg. isrc isn't on album tracks) are left out when missing,
+// so merging a partial record doesn't wipe what a full one wrote.
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct ArtistBundle {
+    id: RecordId,
+    name: String,
+    genres: Option<Vec<String>>,
+    popularity: Option<u32>,
+    followers: Option<u32>,
+    images: Option<Vec<String>>,
+    /// track uris, see [`super::ArtistExpand`]
+    top_tracks: Option<Vec<String>>,
+    /// album uri, see [`super::ArtistExpand`]
+    latest_release: Option<String>,
+    /// set when written from a full artist, see [`fetched`]
+    fetched: Option<DateTime<Utc>>,
+}
+
+impl ArtistBundle {
+    fn from_full(artist: FullArtist, extra: ArtistExpansion) -> Self {
+        Self {
+            id: RecordId::from(("artist", &artist.id.to_string())),
+            name: artist.name,
+            genres: Some(artist.genres),
+            popularity: Some(artist.popularity),
+            followers: Some(artist.followers.total),
+            images: Some(artist.images.into_iter().map(|i| i.url).collect()),
+            top_tracks: extra.top_tracks,
+            latest_release: extra.latest_release,
+            fetched: Some(Utc::now()),
+        }
+    }
+
+    fn from_simplified(artists: &[SimplifiedArtist]) -> Vec<Self> {
+        artists
+            .iter()
+            .filter_map(|a| {
+                Some(Self {
+                    id: RecordId::from(("artist", &a.id.as_ref()?.to_string())),
+                    name: a.name.clone(),
+                    genres: None,
+                    popularity: None,
+                    followers: None,
+                    images: None,
+                    top_tracks: None,
+                    latest_release: None,
+                    fetched: None,
+                })
             })
+            .collect_vec()
+    }
+}
+
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct AlbumBundle {
+    id: RecordId,
+    name: String,
+    album_type: Option<AlbumType>,
+    release_date: Option<String>,
+    release_date_precision: Option<DatePrecision>,
+    popularity: Option<u32>,
+    upc: Option<String>,
+    total_tracks: Option<u32>,
+    image: Option<String>,
+    /// set when written from a full album, see [`fetched`]
+    fetched: Option<DateTime<Utc>>,
+}
+
+impl AlbumBundle {
+    fn from_full(album: &FullAlbum) -> Self {
+        Self {
+            id: RecordId::from(("album", &album.id.to_string())),
+            name: album.name.clone(),
+            album_type: Some(album.album_type),
+            release_date: Some(album.release_date.clone()),
+            release_date_precision: Some(album.release_date_precision),
+            popularity: Some(album.popularity),
+            upc: album.external_ids.get("upc").cloned(),
+            total_tracks: Some(album.tracks.total),
+            image: album.images.first().map(|i| i.url.clone()),
+            fetched: Some(Utc::now()),
+        }
+    }
+
+    fn from_simplified(album: &SimplifiedAlbum) -> Option<Self> {
+        Some(Self {
+            id: RecordId::from(("album", &album.id.as_ref()?.to_string())),
+            name: album.name.clone(),
+            album_type: None,
+            release_date: album.release_date.clone(),
+            release_date_precision: None,
+            popularity: None,
+            upc: None,
+            total_tracks: None,
+            image: album.images.first().map(|i| i.url.clone()),
+            fetched: None,
         })
-        .collect();
-    let tracks = track_bundle.iter().map(|b| b.id.clone()).collect();
+    }
+}
 
-    #[derive(Debug, Deserialize, Serialize)]
-    struct AlbumBundle {
-        id: RecordId,
-        artist: Vec<RecordId>,
-        name: String,
-        track: Vec<RecordId>,
-        spotify_meta: rspotify::model::FullAlbum,
+#[serde_with::skip_serializing_none]
+#[derive(Debug, Clone, Deserialize, Serialize)]
+struct TrackBundle {
+    id: RecordId,
+    name: String,
+    duration_ms: i64,
+    explicit: bool,
+    disc_number: i32,
+    track_number: u32,
+    isrc: Option<String>,
+    popularity: Option<u32>,
+    /// only known when fetched for a market
+    is_playable: Option<bool>,
+    /// the version spotify plays in our market instead, when it relinked this one
+    #[serialize_always]
+    relinked: Option<String>,
+    /// set when written from a full track, see [`fetched`]
+    fetched: Option<DateTime<Utc>>,
+}
+
+impl TrackBundle {
+    fn from_full(track: &FullTrack) -> Option<Self> {
+        let (id, relinked) = shared_id(&track.id, &track.linked_from)?;
+        Some(Self {
+            id: RecordId::from(("track", &id)),
+            name: track.name.clone(),
+            duration_ms: track.duration.num_milliseconds(),
+            explicit: track.explicit,
+            disc_number: track.disc_number,
+            track_number: track.track_number,
+            isrc: track.external_ids.get("isrc").cloned(),
+            popularity: Some(track.popularity),
+            is_playable: track.is_playable,
+            relinked,
+            fetched: Some(Utc::now()),
+        })
     }
 
-    let bundle = AlbumBundle {
-        id: album_id,
-        artist: artists,
-        name: album.name.clone(),
-        track: tracks,
-        spotify_meta: album,
-    };
+    fn from_simplified(track: &SimplifiedTrack) -> Option<Self> {
+        let (id, relinked) = shared_id(&track.id, &track.linked_from)?;
+        Some(Self {
+            id: RecordId::from(("track", &id)),
+            name: track.name.clone(),
+            duration_ms: track.duration.num_milliseconds(),
+            explicit: track.explicit,
+            disc_number: track.disc_number,
+            track_number: track.track_number,
+            isrc: None,
+            popularity: None,
+            is_playable: track.is_playable,
+            relinked,
+            fetched: None,
+        })
+    }
+}
+
+/// The id a track was shared (and asked for) by, and the one spotify relinked it to if it did.
+/// Tracks are stored under the shared id, so links keep finding them.
+fn shared_id(
+    id: &Option<TrackId<'static>>,
+    linked_from: &Option<TrackLink>,
+) -> Option<(String, Option<String>)> {
+    let id = id.as_ref()?.to_string();
+    match linked_from.as_ref().and_then(|l| l.id.as_ref()) {
+        Some(shared) => Some((shared.to_string(), Some(id))),
+        None => Some((id, None)),
+    }
+}
+
+/// Of these ids (uris) in `table`, the ones which have been fully fetched,
+/// and if `max_age` is given, not longer ago than that
+#[throws(eyre::Report)]
+pub async fn fetched(
+    db: &MyDb,
+    table: &str,
+    ids: &[String],
+    max_age: Option<chrono::Duration>,
+) -> HashSet<String> {
+    let query = "SELECT record::id(id) AS id, fetched FROM $ids WHERE fetched != NONE";
+
+    #[derive(Debug, Deserialize)]
+    struct Row {
+        id: String,
+        fetched: DateTime<Utc>,
+    }
+
+    let ids = ids
+        .iter()
+        .map(|id| RecordId::from((table, id)))
+        .collect_vec();
+    let rows: Vec<Row> = db.query(query).bind(("ids", ids)).await?.take(0)?;
+
+    let since = max_age.map(|a| Utc::now() - a);
+    rows.into_iter()
+        .filter(|r| since.map_or(true, |since| r.fetched > since))
+        .map(|r| r.id)
+        .collect()
+}
+
+#[throws(eyre::Report)]
+async fn put_artists(db: &MyDb, artists: Vec<ArtistBundle>) -> Vec<RecordId> {
+    let ids = artists.iter().map(|a| a.id.clone()).collect_vec();
+    for a in artists {
+        let _res: Option<()> = db.upsert(a.id.clone()).merge(a).await?;
+    }
+    ids
+}
+
+#[throws(eyre::Report)]
+async fn put_album(db: &MyDb, album: AlbumBundle, artists: Vec<ArtistBundle>) {
+    let query = r#"
+        UPSERT $id MERGE $album;
+        DELETE by WHERE in = $id;
+        RELATE $id->by->$artists;
+    "#;
+
+    let artists = put_artists(db, artists).await?;
+    db.query(query)
+        .bind(("id", album.id.clone()))
+        .bind(("album", album))
+        .bind(("artists", artists))
+        .await?
+        .check()?;
+}
+
+#[throws(eyre::Report)]
+async fn put_track(
+    db: &MyDb,
+    track: TrackBundle,
+    album: Option<RecordId>,
+    artists: Vec<ArtistBundle>,
+) {
+    let query = r#"
+        UPSERT $id MERGE $track;
+        DELETE on, by WHERE in = $id;
+        IF $album != NONE { RELATE $id->on->$album };
+        RELATE $id->by->$artists;
+    "#;
+
+    let artists = put_artists(db, artists).await?;
+    db.query(query)
+        .bind(("id", track.id.clone()))
+        .bind(("track", track))
+        .bind(("album", album))
+        .bind(("artists", artists))
+        .await?
+        .check()?;
+}
+
+#[throws(eyre::Report)]
+#[instrument(err, skip_all, fields(name = &album.name))]
+pub async fn add_full_album(db: &MyDb, album: FullAlbum) {
+    let bundle = AlbumBundle::from_full(&album);
+    let album_id = bundle.id.clone();
+    put_album(db, bundle, ArtistBundle::from_simplified(&album.artists)).await?;
+
+    for t in album.tracks.items.iter() {
+        let Some(track) = TrackBundle::from_simplified(t) else {
+            continue;
+        };
+        let artists = ArtistBundle::from_simplified(&t.artists);
+        put_track(db, track, Some(album_id.clone()), artists).await?;
+    }
+}
 
-    //mvp is some way to bundle togeather
-    // make this operate on list of FullAlbum
+#[throws(eyre::Report)]
+#[instrument(err, skip_all, fields(name = &track.name))]
+pub async fn add_full_track(db: &MyDb, track: FullTrack) {
+    let bundle =
+        TrackBundle::from_full(&track).context(format!("no TrackId for {})", &track.name))?;
+
+    let album = AlbumBundle::from_simplified(&track.album);
+    let album_id = album.as_ref().map(|a| a.id.clone());
+    if let Some(album) = album {
+        put_album(
+            db,
+            album,
+            ArtistBundle::from_simplified(&track.album.artists),
+        )
+        .await?;
+    }
 
-    let _res: Option<()> = db.update(bundle.id.clone()).content(bundle).await?;
+    let artists = ArtistBundle::from_simplified(&track.artists);
+    put_track(db, bundle, album_id, artists).await?;
 }
 
 #[throws(eyre::Report)]
-#[instrument(err, skip(db))]
-pub async fn add_full_track(db: &MyDb, track: rspotify::model::FullTrack) {
+#[instrument(err, skip_all, fields(name = &artist.name))]
+pub async fn add_full_artist(db: &MyDb, artist: FullArtist, extra: ArtistExpansion) {
+    put_artists(db, vec![ArtistBundle::from_full(artist, extra)]).await?;
+}
+
+/// Store the playlist and its (depaginated) items, in order.
+/// Tracks in the playlist are stored too, episodes and local files only as an item.
+/// A track which can't be stored keeps its place by uri, without the link, rather than losing the playlist.
+#[throws(eyre::Report)]
+#[instrument(err, skip_all, fields(name = &playlist.name))]
+pub async fn add_full_playlist(db: &MyDb, playlist: FullPlaylist) {
+    #[derive(Debug, Deserialize, Serialize)]
+    struct ItemBundle {
+        track: Option<RecordId>,
+        uri: Option<String>,
+        added_at: Option<DateTime<Utc>>,
+        added_by: Option<String>,
+        is_local: bool,
+    }
+
     #[derive(Debug, Deserialize, Serialize)]
-    struct TrackBundle {
+    struct PlaylistBundle {
         id: RecordId,
         name: String,
-        album: Option<RecordId>,
+        description: Option<String>,
+        owner: String,
+        snapshot_id: String,
+        item: Vec<ItemBundle>,
     }
 
-    let id = track
-        .id
-        .context(format!("no TrackId for {})", &track.name))?
-        .to_string();
-
-    let album = track
-        .album
-        .id
-        .map(|id| RecordId::from(("album".to_string(), id.to_string())));
+    let mut items = Vec::new();
+    let mut failed = Vec::new();
+    for item in playlist.tracks.items {
+        let (track, uri) = match item.track {
+            Some(PlayableItem::Track(t)) => {
+                // fetched for a market, so it may be relinked. the playlist holds the shared id
+                let uri = shared_id(&t.id, &t.linked_from).map(|(id, _)| id);
+                let mut track = uri.clone().map(|id| RecordId::from(("track", &id)));
+                // errors are logged by instrument
+                if t.id.is_some() && add_full_track(db, t).await.is_err() {
+                    failed.extend(uri.clone());
+                    track = None;
+                }
+                (track, uri)
+            }
+            Some(PlayableItem::Episode(e)) => (None, Some(e.id.to_string())),
+            None => (None, None),
+        };
+        items.push(ItemBundle {
+            track,
+            uri,
+            added_at: item.added_at,
+            added_by: item.added_by.map(|u| u.id.to_string()),
+            is_local: item.is_local,
+        });
+    }
 
-    let bundle = TrackBundle {
-        id: RecordId::from(("track".to_string(), id)),
-        name: track.name,
-        album,
+    let bundle = PlaylistBundle {
+        id: RecordId::from(("playlist", &playlist.id.to_string())),
+        name: playlist.name,
+        description: playlist.description,
+        owner: playlist.owner.id.to_string(),
+        snapshot_id: playlist.snapshot_id,
+        item: items,
     };
 
-    let _res: Option<()> = db.update(bundle.id.clone()).content(bundle).await?;
+    if !failed.is_empty() {
+        tracing::warn!(?failed, "stored the playlist without these tracks");
+    }
+    let _res: Option<()> = db.upsert(bundle.id.clone()).content(bundle).await?;
+}
+
+/// A spotify profile. It goes in the `user` table with the chat users, keyed by uri so they don't clash.
+#[throws(eyre::Report)]
+#[instrument(err, skip_all, fields(id = %user.id))]
+pub async fn add_user(db: &MyDb, user: PublicUser, playlists: Option<Vec<String>>) {
+    #[serde_with::skip_serializing_none]
+    #[derive(Debug, Deserialize, Serialize)]
+    struct UserBundle {
+        name: Option<String>,
+        followers: Option<u32>,
+        /// left alone unless they were listed this time
+        playlists: Option<Vec<RecordId>>,
+        fetched: DateTime<Utc>,
+    }
+
+    let bundle = UserBundle {
+        name: user.display_name,
+        followers: user.followers.map(|f| f.total),
+        playlists: playlists.map(|p| {
+            p.iter()
+                .map(|uri| RecordId::from(("playlist", uri)))
+                .collect()
+        }),
+        fetched: Utc::now(),
+    };
+    db.query("UPSERT $id MERGE $user")
+        .bind(("id", RecordId::from(("user", &user.id.to_string()))))
+        .bind(("user", bundle))
+        .await?
+        .check()?;
 }
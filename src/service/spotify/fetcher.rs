@@ -1,7 +1,4 @@
-use std::{
-    collections::HashMap,
-    future::Future,
-};
+use std::{collections::HashMap, future::Future};
 
 use chrono::Local;
 use culpa::{throw, throws};
@@ -10,19 +7,17 @@ use itertools::Itertools;
 use rspotify::{
     clients::BaseClient,
     model::{
-        FullAlbum, FullPlaylist, FullTrack, ItemPositions, Page, PlayableId, PlaylistItem, SimplifiedTrack, TrackId,
+        EpisodeId, FullAlbum, FullPlaylist, FullTrack, ItemPositions, Market, Page, PlayableId,
+        PlayableItem, PlaylistItem, SimplifiedTrack, TrackId, TrackLink,
     },
     prelude::OAuthClient,
     AuthCodeSpotify, DEFAULT_PAGINATION_CHUNKS,
 };
 use tracing::warn;
 
-use crate::{
-    prelude::Loggable,
-    utils::when_even::OnError,
-};
+use crate::utils::diff::{anchor_fixed, Actions, SequenceOptions};
 
-use super::RateLimiter;
+use super::{Client, Pager, RateLimiter};
 
 /// requirements:
 /// - fetch tracks for albums and albums for tracks
@@ -49,18 +44,20 @@ use super::RateLimiter;
 #[throws(eyre::Error)]
 #[tracing::instrument("paginate", skip_all)]
 pub async fn depageinate_album(
-    client: &AuthCodeSpotify,
+    client: &Client,
     ratelimiter: &RateLimiter,
+    pager: &Pager,
+    market: Option<Market>,
     album: &mut FullAlbum,
 ) {
     if album.tracks.next.is_some() {
-        let mut offset = album.tracks.offset;
+        let mut offset = album.tracks.offset + album.tracks.items.len() as u32;
         while album.tracks.next.is_some() {
-            let page = match ratelimiter
-                .with_rate_limit_acquired(|| {
+            let page = match pager
+                .with_rate_limit(ratelimiter, || {
                     client.album_track_manual(
                         album.id.clone(),
-                        None,
+                        market,
                         Some(DEFAULT_PAGINATION_CHUNKS),
                         Some(offset),
                     )
@@ -85,8 +82,10 @@ pub async fn depageinate_album(
 #[throws(eyre::Error)]
 #[tracing::instrument(skip_all)]
 pub async fn depageinate_album_fast<Fut>(
-    client: &AuthCodeSpotify,
+    client: &Client,
     ratelimiter: &RateLimiter,
+    pager: &Pager,
+    market: Option<Market>,
     album: &mut FullAlbum,
     update: impl Fn(Page<SimplifiedTrack>) -> Fut,
 ) where
@@ -95,16 +94,15 @@ pub async fn depageinate_album_fast<Fut>(
     // TODO feed data in real time
     if album.tracks.next.is_some() {
         let mut pages = vec![];
-        let mut offset = album.tracks.offset;
+        let mut offset = album.tracks.offset + album.tracks.items.len() as u32;
         let mut ts = FuturesUnordered::new();
         while offset < album.tracks.total {
-            // EDIT: refactored so lease is dropped as soon as connection is finished
-            // let needs_lease = !ts.is_empty(); //first task reuses future from the client
+            // each page waits for its own connection from the module, and gives it back when done
             let id = album.id.clone();
-            let f = ratelimiter.with_rate_limit_acquired(move || {
+            let f = pager.with_rate_limit(ratelimiter, move || {
                 client.album_track_manual(
                     id.clone(),
-                    None,
+                    market,
                     Some(DEFAULT_PAGINATION_CHUNKS),
                     Some(offset),
                 )
@@ -132,19 +130,21 @@ pub async fn depageinate_album_fast<Fut>(
 #[throws(eyre::Error)]
 #[tracing::instrument(skip_all)]
 pub async fn depageinate_playlist(
-    client: &AuthCodeSpotify,
+    client: &Client,
     ratelimiter: &RateLimiter,
+    pager: &Pager,
+    market: Option<Market>,
     pl: &mut FullPlaylist,
 ) {
     if pl.tracks.next.is_some() {
-        let mut offset = pl.tracks.offset;
+        let mut offset = pl.tracks.offset + pl.tracks.items.len() as u32;
         while pl.tracks.next.is_some() {
-            let page = match ratelimiter
-                .with_rate_limit_acquired(|| {
+            let page = match pager
+                .with_rate_limit(ratelimiter, || {
                     client.playlist_items_manual(
                         pl.id.clone(),
                         None,
-                        None,
+                        market,
                         Some(DEFAULT_PAGINATION_CHUNKS),
                         Some(offset),
                     )
@@ -169,8 +169,10 @@ pub async fn depageinate_playlist(
 #[throws(eyre::Error)]
 #[tracing::instrument("paginate", skip_all)]
 pub async fn depageinate_playlist_fast<Fut>(
-    client: &AuthCodeSpotify,
+    client: &Client,
     ratelimiter: &RateLimiter,
+    pager: &Pager,
+    market: Option<Market>,
     pl: &mut FullPlaylist,
     // mut update: impl Sink<Page<PlaylistItem>, Error: Debug> + std::marker::Unpin,
     update: impl Fn(Page<PlaylistItem>) -> Fut,
@@ -183,14 +185,13 @@ pub async fn depageinate_playlist_fast<Fut>(
         let mut offset = pl.tracks.offset + pl.tracks.items.len() as u32;
         let mut ts = FuturesUnordered::new();
         while offset < pl.tracks.total {
-            // EDIT: refactored so lease is dropped as soon as connection is finished
-            // let needs_lease = !ts.is_empty(); //first task reuses future from the client
+            // each page waits for its own connection from the module, and gives it back when done
             let id = pl.id.clone();
-            let f = ratelimiter.with_rate_limit_acquired(move || {
+            let f = pager.with_rate_limit(ratelimiter, move || {
                 client.playlist_items_manual(
                     id.clone(),
                     None,
-                    None,
+                    market,
                     Some(DEFAULT_PAGINATION_CHUNKS),
                     Some(offset),
                 )
@@ -216,63 +217,127 @@ pub async fn depageinate_playlist_fast<Fut>(
     }
 }
 
-/// Does not support podcasts because I don't support Joe Rogan.
-#[throws(eyre::Error)]
-#[tracing::instrument(skip_all)]
-async fn playlist_sync(
-    client: &AuthCodeSpotify,
-    ratelimiter: &RateLimiter,
-    pl: FullPlaylist,
-    target: Vec<TrackId<'static>>,
-) {
-    assert_eq!(
-        pl.tracks.total as usize,
-        pl.tracks.items.len(),
-        "function expects a depaginated playlist"
-    );
+/// A playlist item, as far as syncing is concerned
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Entry {
+    Track(TrackId<'static>),
+    /// never in the target, so it gets removed
+    Episode(EpisodeId<'static>),
+    /// local files and tracks spotify no longer knows (by position), which the api can't touch
+    Fixed(usize),
+}
+
+impl Entry {
+    fn new(i: usize, item: &PlaylistItem) -> Self {
+        match &item.track {
+            // with a market the track may be relinked, the playlist still holds the original
+            Some(PlayableItem::Track(FullTrack {
+                linked_from: Some(TrackLink { id: Some(id), .. }),
+                ..
+            }))
+            | Some(PlayableItem::Track(FullTrack { id: Some(id), .. })) => {
+                Entry::Track(id.clone_static())
+            }
+            Some(PlayableItem::Episode(e)) => Entry::Episode(e.id.clone_static()),
+            _ => Entry::Fixed(i),
+        }
+    }
+
+    fn playable(self) -> Option<PlayableId<'static>> {
+        match self {
+            Entry::Track(id) => Some(PlayableId::Track(id)),
+            Entry::Episode(id) => Some(PlayableId::Episode(id)),
+            Entry::Fixed(_) => None,
+        }
+    }
+}
 
-    let current: Result<Vec<_>, PlaylistItem> = pl
+/// Work out the api requests needed to get from the (depaginated) playlist to the target.
+/// Nothing is sent to spotify, so this doubles as a dry run of syncing.
+/// With `no_delete` the target is only ever prepended, see [`crate::utils::diff::prepend_only`].
+/// Local files and unavailable tracks stay where they are, relative to the tracks around them.
+pub fn plan_sync(
+    pl: &FullPlaylist,
+    target: Vec<TrackId<'static>>,
+    no_delete: bool,
+) -> Vec<Actions<PlayableId<'static>>> {
+    let current = pl
         .tracks
         .items
+        .iter()
+        .enumerate()
+        .map(|(i, t)| Entry::new(i, t))
+        .collect_vec();
+    let target = target.into_iter().map(Entry::Track).collect_vec();
+
+    let actions = if no_delete {
+        crate::utils::diff::prepend_only(&current, target, Default::default())
+    } else {
+        let target = anchor_fixed(&current, target, |e| matches!(e, Entry::Fixed(_)));
+        let opts = SequenceOptions {
+            delete_positional: true, // only removes the copies we mean to, keeping added dates
+            ..Default::default()
+        };
+        crate::utils::diff::sequence(current, target, opts)
+    };
+
+    // fixed entries only show up if it fell back to a full replace, where they are lost anyway
+    actions
         .into_iter()
-        .map(|t| -> Result<TrackId<'static>, PlaylistItem> {
-            match t.track {
-                Some(rspotify::model::PlayableItem::Track(FullTrack { id: Some(id), .. })) => {
-                    Ok(id)
-                }
-                _ => Err(t),
-            }
+        .map(|a| a.filter_map(Entry::playable))
+        .filter(|a| match a {
+            Actions::Append(v) | Actions::Add(v, _) | Actions::DeleteAll(v) => !v.is_empty(),
+            Actions::Delete(v) => !v.is_empty(),
+            Actions::Replace(_) | Actions::Move { .. } => true,
         })
-        .try_collect();
-
-    let actions = match current {
-        Ok(current) => crate::utils::diff::sequence(current, target.clone(), Default::default()),
-        Err(e) => {
-            tracing::warn!(
-                "Using full replacement due to weird stuff in playlist: {:?}",
-                e
-            );
-            crate::utils::diff::full_replace(target.clone(), Default::default())
-        }
-    };
+        .collect_vec()
+}
+
+/// How a sync went, after checking the playlist against the target
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncOutcome {
+    /// playlist already matched, nothing was sent
+    Unchanged,
+    /// matched after this many rounds of incremental updates
+    Synced { attempts: usize },
+    /// incremental updates kept missing, so the playlist was replaced wholesale
+    Replaced { attempts: usize },
+    /// still doesn't match, giving up
+    Failed { attempts: usize },
+}
 
+/// Send the planned requests, in order.
+/// Does not support podcasts because I don't support Joe Rogan.
+#[throws(eyre::Error)]
+#[tracing::instrument(skip_all, fields(n = actions.len()))]
+pub async fn playlist_apply(
+    client: &AuthCodeSpotify,
+    ratelimiter: &RateLimiter,
+    pager: &Pager,
+    pl: &FullPlaylist,
+    actions: Vec<Actions<PlayableId<'static>>>,
+) {
+    // positions in deletes and moves are relative to the playlist after the previous request,
+    // so each request names the snapshot returned by the one before it.
+    // for the same reason, a failed request aborts the rest of the plan.
+    // each request leases its own connection, so writes queue behind fetches like everything else.
+    let mut snapshot = Some(pl.snapshot_id.clone());
     for a in actions {
         match a {
             crate::utils::diff::Actions::Append(v) => {
-                let items = v.into_iter().map(|t| t.into()).collect_vec();
+                let items = v;
                 let foo = || client.playlist_add_items(pl.id.clone(), items.clone(), None);
-                let ret = ratelimiter.with_rate_limit(foo, true).await;
-                ret.log_and_drop::<OnError>();
+                let ret = pager.with_rate_limit(ratelimiter, foo).await?;
+                snapshot = Some(ret.snapshot_id);
             }
             crate::utils::diff::Actions::Add(v, i) => {
-                let items = v.into_iter().map(|t| t.into()).collect_vec();
+                let items = v;
                 let foo =
                     || client.playlist_add_items(pl.id.clone(), items.clone(), Some(i as u32));
-                let ret = ratelimiter.with_rate_limit(foo, true).await;
-                ret.log_and_drop::<OnError>();
+                let ret = pager.with_rate_limit(ratelimiter, foo).await?;
+                snapshot = Some(ret.snapshot_id);
             }
             crate::utils::diff::Actions::Delete(v) => {
-                tracing::warn!("positional delete is broken");
                 let mut hm = HashMap::<_, Vec<u32>>::new();
                 for (i, t) in v {
                     hm.entry(t).or_insert(Default::default()).push(i as u32);
@@ -282,44 +347,68 @@ async fn playlist_sync(
                     let items = hm
                         .iter()
                         .map(|(id, p)| ItemPositions {
-                            id: PlayableId::Track(id.clone_static()),
+                            id: id.clone_static(),
                             positions: &p,
                         })
                         .collect_vec();
                     client.playlist_remove_specific_occurrences_of_items(
                         pl.id.clone(),
                         items,
-                        Some(&pl.snapshot_id),
+                        snapshot.as_deref(),
                     )
                 };
-                let ret = ratelimiter.with_rate_limit(foo, true).await;
-                ret.log_and_drop::<OnError>();
+                let ret = pager.with_rate_limit(ratelimiter, foo).await?;
+                snapshot = Some(ret.snapshot_id);
             }
             crate::utils::diff::Actions::DeleteAll(v) => {
-                let items = v.into_iter().map(|t| t.into()).collect_vec();
+                let items = v;
                 let foo = || {
                     client.playlist_remove_all_occurrences_of_items(
                         pl.id.clone(),
                         items.clone(),
-                        Some(&pl.snapshot_id),
+                        snapshot.as_deref(),
+                    )
+                };
+                let ret = pager.with_rate_limit(ratelimiter, foo).await?;
+                snapshot = Some(ret.snapshot_id);
+            }
+            crate::utils::diff::Actions::Move {
+                range_start,
+                range_length,
+                insert_before,
+            } => {
+                let foo = || {
+                    client.playlist_reorder_items(
+                        pl.id.clone(),
+                        Some(range_start as i32),
+                        Some(insert_before as i32),
+                        Some(range_length as u32),
+                        snapshot.as_deref(),
                     )
                 };
-                let ret = ratelimiter.with_rate_limit(foo, true).await;
-                ret.log_and_drop::<OnError>();
+                let ret = pager.with_rate_limit(ratelimiter, foo).await?;
+                snapshot = Some(ret.snapshot_id);
             }
             crate::utils::diff::Actions::Replace(v) => {
-                let items = v.into_iter().map(|t| t.into()).collect_vec();
+                let items = v;
                 let foo = || client.playlist_replace_items(pl.id.clone(), items.clone());
-                let ret = ratelimiter.with_rate_limit(foo, true).await;
-                ret.log_and_drop::<OnError>();
+                pager.with_rate_limit(ratelimiter, foo).await?;
+                // replace doesn't return a snapshot, anything after it is an append
+                snapshot = None;
             }
         }
     }
+}
 
-    // TODO rescan
-
-    // update description
-    let desc = pl.description.unwrap_or_default();
+/// Stamp the time of the last sync that changed anything at the end of the description
+#[throws(eyre::Error)]
+pub async fn stamp_description(
+    client: &AuthCodeSpotify,
+    ratelimiter: &RateLimiter,
+    pager: &Pager,
+    pl: &FullPlaylist,
+) {
+    let desc = pl.description.clone().unwrap_or_default();
     let desc = desc.split("sync: ").next().unwrap();
     let mut desc = desc.trim().to_string();
     if !desc.is_empty() {
@@ -328,6 +417,5 @@ async fn playlist_sync(
     let desc = format!("{desc}sync: {}", Local::now());
 
     let foo = || client.playlist_change_detail(pl.id.clone(), None, None, Some(&desc), None);
-    let ret = ratelimiter.with_rate_limit(foo, true).await;
-    ret.log_and_drop::<OnError>();
+    pager.with_rate_limit(ratelimiter, foo).await?;
 }
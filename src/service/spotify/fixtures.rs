@@ -0,0 +1,329 @@
+//! Recording real spotify responses, to replay them in tests (see `MockSpotify::replay`).
+//! With `record_fixtures` set in the config, the module talks to spotify through a local proxy
+//! which appends every request and response to that file, one json object per line.
+
+use std::{future::Future, io::Write, sync::Arc};
+
+use rspotify::http::{BaseHttpClient, Headers, HttpClient, HttpError, Query};
+use serde_json::Value;
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream},
+};
+
+use crate::{prelude::*, utils::when_even::OnError};
+
+/// spotify's api, what the proxy forwards to unless `api_base_url` says otherwise
+pub const SPOTIFY_API: &str = "https://api.spotify.com/v1/";
+
+/// one request and what spotify answered
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Exchange {
+    pub method: String,
+    /// relative to the api base, see [`normalize`]
+    pub target: String,
+    #[serde(default)]
+    pub request: Value,
+    pub status: u16,
+    #[serde(default)]
+    pub retry_after: Option<String>,
+    pub response: String,
+}
+
+#[cfg(test)]
+impl Exchange {
+    /// what a replayed request has to match
+    pub fn key(&self) -> String {
+        format!("{} {}", self.method, self.target)
+    }
+}
+
+/// Path relative to the api base with the query sorted, so the same request always looks the same.
+/// rspotify keeps its query in a hashmap, so the order changes between runs.
+pub fn normalize(target: &str) -> String {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let path = path.trim_matches('/');
+    let path = path.strip_prefix("v1/").unwrap_or(path);
+    let mut query = url::form_urlencoded::parse(query.as_bytes())
+        .into_owned()
+        .collect::<Vec<_>>();
+    if query.is_empty() {
+        return path.to_string();
+    }
+    query.sort();
+    let query = url::form_urlencoded::Serializer::new(String::new())
+        .extend_pairs(query)
+        .finish();
+    format!("{}?{}", path, query)
+}
+
+#[cfg(test)]
+#[throws(eyre::Report)]
+pub fn load(path: &str) -> Vec<Exchange> {
+    std::fs::read_to_string(path)?
+        .lines()
+        .filter(|l| !l.trim().is_empty())
+        .map(serde_json::from_str)
+        .collect::<Result<_, _>>()?
+}
+
+pub struct Request {
+    pub method: String,
+    pub target: String,
+    pub authorization: Option<String>,
+    pub body: Value,
+}
+
+pub struct Response {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: String,
+}
+
+#[cfg(test)]
+impl Response {
+    pub fn ok(body: Value) -> Self {
+        Response {
+            status: 200,
+            headers: vec![],
+            body: body.to_string(),
+        }
+    }
+
+    pub fn status(status: u16) -> Self {
+        Response {
+            status,
+            headers: vec![],
+            body: String::new(),
+        }
+    }
+}
+
+/// Just enough http/1.1 for rspotify: keep-alive connections, bodies by content-length.
+pub async fn serve<F, Fut>(listener: TcpListener, handler: F)
+where
+    F: Fn(Request) -> Fut + Send + Sync + 'static,
+    Fut: Future<Output = Response> + Send + 'static,
+{
+    let handler = Arc::new(handler);
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            return;
+        };
+        tokio::spawn(connection(stream, handler.clone()));
+    }
+}
+
+/// requests are answered in order until the client hangs up
+async fn connection<F, Fut>(stream: TcpStream, handler: Arc<F>)
+where
+    F: Fn(Request) -> Fut,
+{
+    let mut stream = BufReader::new(stream);
+    loop {
+        let mut line = String::new();
+        if stream.read_line(&mut line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = line.split_whitespace();
+        let (Some(method), Some(target)) = (parts.next(), parts.next()) else {
+            return;
+        };
+
+        let mut len = 0;
+        let mut authorization = None;
+        loop {
+            let mut header = String::new();
+            if stream.read_line(&mut header).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let Some((k, v)) = header.split_once(':') else {
+                continue;
+            };
+            if k.eq_ignore_ascii_case("content-length") {
+                len = v.trim().parse().unwrap_or(0);
+            } else if k.eq_ignore_ascii_case("authorization") {
+                authorization = Some(v.trim().to_string());
+            }
+        }
+        let mut body = vec![0; len];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+
+        let request = Request {
+            method: method.to_string(),
+            target: target.to_string(),
+            authorization,
+            // the token endpoint is form encoded, nothing reads that
+            body: serde_json::from_slice(&body).unwrap_or(Value::Null),
+        };
+        let response = handler(request).await;
+
+        let mut out = format!(
+            "HTTP/1.1 {} {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n",
+            response.status,
+            reason(response.status),
+            response.body.len()
+        );
+        for (k, v) in response.headers {
+            out.push_str(&format!("{}: {}\r\n", k, v));
+        }
+        out.push_str("\r\n");
+        out.push_str(&response.body);
+        if stream.get_mut().write_all(out.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn reason(status: u16) -> &'static str {
+    match status {
+        200 => "OK",
+        201 => "Created",
+        400 => "Bad Request",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        502 => "Bad Gateway",
+        _ => "Unknown",
+    }
+}
+
+/// Start the recording proxy in front of `upstream`, returns the url to use as the api base.
+#[throws(eyre::Report)]
+pub async fn record(path: String, upstream: String) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let url = format!("http://{}/", listener.local_addr()?);
+    let upstream = Url::parse(&upstream)?;
+    tracing::warn!(%path, %upstream, "recording spotify fixtures");
+
+    let http = HttpClient::default();
+    let handler = move |req: Request| {
+        let http = http.clone();
+        let upstream = upstream.clone();
+        let path = path.clone();
+        async move {
+            let exchange = forward(&http, &upstream, req).await;
+            append(&path, &exchange).log_and_drop::<OnError>();
+            let mut response = Response {
+                status: exchange.status,
+                headers: vec![],
+                body: exchange.response,
+            };
+            if let Some(r) = exchange.retry_after {
+                response.headers.push(("Retry-After".to_string(), r));
+            }
+            response
+        }
+    };
+    tokio::spawn(serve(listener, handler));
+    url
+}
+
+async fn forward(http: &HttpClient, upstream: &Url, req: Request) -> Exchange {
+    let target = req.target.trim_start_matches('/');
+    let mut exchange = Exchange {
+        method: req.method.clone(),
+        target: normalize(target),
+        request: req.body.clone(),
+        status: 200,
+        retry_after: None,
+        response: String::new(),
+    };
+
+    let url = match upstream.join(target) {
+        Ok(url) => url,
+        Err(e) => {
+            exchange.status = 400;
+            exchange.response = e.to_string();
+            return exchange;
+        }
+    };
+    let headers: Headers = req
+        .authorization
+        .into_iter()
+        .map(|a| ("authorization".to_string(), a))
+        .collect();
+    let headers = Some(&headers);
+
+    let ret = match req.method.as_str() {
+        "GET" => http.get(url.as_str(), headers, &Query::new()).await,
+        "POST" => http.post(url.as_str(), headers, &req.body).await,
+        "PUT" => http.put(url.as_str(), headers, &req.body).await,
+        "DELETE" => http.delete(url.as_str(), headers, &req.body).await,
+        m => {
+            exchange.status = 400;
+            exchange.response = format!("can't forward {}", m);
+            return exchange;
+        }
+    };
+
+    match ret {
+        Ok(body) => exchange.response = body,
+        Err(HttpError::StatusCode(resp)) => {
+            exchange.status = resp.status().as_u16();
+            exchange.retry_after = resp
+                .headers()
+                .get("Retry-After")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_string);
+            exchange.response = resp.text().await.unwrap_or_default();
+        }
+        Err(e) => {
+            exchange.status = 502;
+            exchange.response = e.to_string();
+        }
+    }
+    exchange
+}
+
+#[throws(eyre::Report)]
+fn append(path: &str, exchange: &Exchange) {
+    let mut file = std::fs::OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)?;
+    writeln!(file, "{}", serde_json::to_string(exchange)?)?;
+}
+
+/// Serves recorded exchanges back, in the order they were recorded.
+/// Once a request has used up its recordings the last one keeps being served.
+#[cfg(test)]
+#[derive(Default)]
+pub struct Replay {
+    exchanges: std::collections::HashMap<String, Vec<Exchange>>,
+}
+
+#[cfg(test)]
+impl Replay {
+    pub fn new(exchanges: Vec<Exchange>) -> Self {
+        let mut replay = Replay::default();
+        for e in exchanges {
+            replay.exchanges.entry(e.key()).or_default().push(e);
+        }
+        replay
+    }
+
+    pub fn answer(&mut self, method: &str, target: &str) -> Option<Response> {
+        let key = format!("{} {}", method, normalize(target));
+        let recorded = self.exchanges.get_mut(&key)?;
+        let e = match recorded.len() {
+            0 => return None,
+            1 => recorded[0].clone(),
+            _ => recorded.remove(0),
+        };
+        let mut response = Response {
+            status: e.status,
+            headers: vec![],
+            body: e.response,
+        };
+        if let Some(r) = e.retry_after {
+            response.headers.push(("Retry-After".to_string(), r));
+        }
+        Some(response)
+    }
+}
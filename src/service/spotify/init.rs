@@ -1,27 +1,128 @@
-use std::path::PathBuf;
+use std::{collections::HashSet, io::IsTerminal, path::PathBuf, time::Duration};
 
-use crate::prelude::*;
+use eyre::{ContextCompat, WrapErr};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
 
-use rspotify::{clients::OAuthClient, scopes, AuthCodeSpotify, Credentials, OAuth};
+use crate::{prelude::*, utils::when_even::OnError};
+
+use rspotify::{
+    clients::{BaseClient, OAuthClient},
+    http::HttpClient,
+    model::Market,
+    scopes,
+    sync::Mutex,
+    AuthCodeSpotify, ClientCredsSpotify, ClientResult, Credentials, OAuth, Token,
+};
+
+use super::AuthFlow;
+
+/// Either a logged in user, or just the app (client credentials) which can only read.
+/// Reads go through [`BaseClient`] either way, writes need [`Client::user`].
+#[derive(Debug, Clone)]
+pub enum Client {
+    User(AuthCodeSpotify),
+    App(ClientCredsSpotify),
+}
+
+impl Default for Client {
+    fn default() -> Self {
+        Client::App(Default::default())
+    }
+}
+
+impl Client {
+    /// the logged in client, for anything which changes playlists or the library
+    #[throws(eyre::Error)]
+    pub fn user(&self) -> &AuthCodeSpotify {
+        match self {
+            Client::User(c) => c,
+            Client::App(_) => throw!(eyre::eyre!(
+                "spotify is logged in with client credentials, which are read only. use `flow: auth_code` to write"
+            )),
+        }
+    }
+
+    /// the user's market, for endpoints which want one. the app alone doesn't have one
+    pub fn token_market(&self) -> Option<Market> {
+        match self {
+            Client::User(_) => Some(Market::FromToken),
+            Client::App(_) => None,
+        }
+    }
+}
+
+#[async_trait]
+impl BaseClient for Client {
+    fn get_http(&self) -> &HttpClient {
+        match self {
+            Client::User(c) => c.get_http(),
+            Client::App(c) => c.get_http(),
+        }
+    }
+
+    fn get_token(&self) -> Arc<Mutex<Option<Token>>> {
+        match self {
+            Client::User(c) => c.get_token(),
+            Client::App(c) => c.get_token(),
+        }
+    }
+
+    fn get_creds(&self) -> &Credentials {
+        match self {
+            Client::User(c) => c.get_creds(),
+            Client::App(c) => c.get_creds(),
+        }
+    }
+
+    fn get_config(&self) -> &rspotify::Config {
+        match self {
+            Client::User(c) => c.get_config(),
+            Client::App(c) => c.get_config(),
+        }
+    }
+
+    async fn refetch_token(&self) -> ClientResult<Option<Token>> {
+        match self {
+            Client::User(c) => c.refetch_token().await,
+            Client::App(c) => c.refetch_token().await,
+        }
+    }
+}
 
 #[throws(eyre::Error)]
 #[tracing::instrument(skip_all)]
-pub async fn connect(config: &super::Config) -> AuthCodeSpotify {
+pub async fn connect(config: &super::Config) -> Client {
+    match config.flow {
+        AuthFlow::AuthCode => Client::User(connect_user(config).await?),
+        AuthFlow::ClientCredentials => {
+            let creds = Credentials::new(&config.id, &config.secret);
+            let client = ClientCredsSpotify::with_config(creds, base_config(config));
+            client
+                .request_token()
+                .await
+                .wrap_err("spotify rejected the client credentials")?;
+            tracing::info!("connected to spotify with client credentials, read only");
+            Client::App(client)
+        }
+    }
+}
+
+#[throws(eyre::Error)]
+async fn connect_user(config: &super::Config) -> AuthCodeSpotify {
+    if config.redirect_url.is_empty() || config.token_cache_path.is_empty() {
+        throw!(eyre::eyre!(
+            "spotify `redirect_url` and `token_cache_path` are needed to log in as a user"
+        ));
+    }
+
     let client = {
         let creds = Credentials::new(&config.id, &config.secret);
         let oauth = OAuth {
             redirect_uri: config.redirect_url.clone(),
-            scopes: scopes!(
-                "user-read-playback-position",
-                "playlist-read-collaborative",
-                "playlist-read-private",
-                "user-follow-modify",
-                "user-library-modify",
-                "user-modify-playback-state",
-                "playlist-modify-public",
-                "playlist-modify-private",
-                "ugc-image-upload"
-            ),
+            scopes: user_scopes(),
             ..Default::default()
         };
 
@@ -32,26 +133,27 @@ pub async fn connect(config: &super::Config) -> AuthCodeSpotify {
         let conf = rspotify::Config {
             cache_path,
             token_cached: true,
-            token_refreshing: true,
-            ..Default::default()
+            ..base_config(config)
         };
 
         AuthCodeSpotify::with_config(creds, oauth, conf)
     };
 
     let url = client.get_authorize_url(false)?;
-    // This function requires the `cli` feature enabled.
     tracing::info!("connecting to spotify");
-    client.prompt_for_token(&url).await?; // TODO open server, and fallback to prompt
+    login(&client, &url, &config.token_cache_path).await?;
 
     {
         /* Test connection */
         let guard = client.token.lock().await.unwrap();
-        let token = guard.as_ref().unwrap();
-        tracing::info!(token = token.access_token);
+        let token = guard.as_ref().context("no spotify token after login")?;
+        tracing::debug!(expires_at = ?token.expires_at, "got token");
         drop(guard); // Or else next request hangs
 
-        let user = client.current_user().await.unwrap(); // XXX can fail on ratelimit
+        let user = client
+            .current_user()
+            .await
+            .wrap_err("spotify rejected the token")?;
         tracing::info!(
             name = user.display_name,
             user = user.id.to_string(),
@@ -61,3 +163,177 @@ pub async fn connect(config: &super::Config) -> AuthCodeSpotify {
 
     client
 }
+
+/// what we ask the user for, a cached token without all of these isn't used
+pub fn user_scopes() -> HashSet<String> {
+    scopes!(
+        "user-read-playback-position",
+        "playlist-read-collaborative",
+        "playlist-read-private",
+        "user-follow-modify",
+        "user-library-modify",
+        "user-modify-playback-state",
+        "playlist-modify-public",
+        "playlist-modify-private",
+        "ugc-image-upload"
+    )
+}
+
+/// rspotify's config for either flow, pointed at `api_base_url` and `auth_base_url` if they are set
+fn base_config(config: &super::Config) -> rspotify::Config {
+    let mut conf = rspotify::Config {
+        token_refreshing: true,
+        ..Default::default()
+    };
+    if let Some(url) = &config.api_base_url {
+        conf.api_base_url = url.clone();
+    }
+    if let Some(url) = &config.auth_base_url {
+        conf.auth_base_url = url.clone();
+    }
+    conf
+}
+
+/// how long to wait for someone to log in through the browser
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+/// Use the cached token, refreshing it if it expired.
+/// Without one the user logs in through the browser, and spotify redirects back to a listener on `redirect_url`.
+/// If we can't listen there it falls back to pasting the redirect url on stdin, as long as there is a terminal.
+/// A cached token which can't be refreshed fails straight away without a terminal,
+/// an unattended instance would otherwise sit waiting for a login nobody knows is needed.
+#[throws(eyre::Error)]
+async fn login(client: &AuthCodeSpotify, url: &str, cache_path: &str) {
+    if let Ok(Some(token)) = client.read_token_cache(true).await {
+        let expired = token.is_expired();
+        *client.get_token().lock().await.unwrap() = Some(token);
+        if !expired {
+            return;
+        }
+        match client.refetch_token().await {
+            Ok(Some(token)) => {
+                tracing::info!("refreshed expired spotify token");
+                *client.get_token().lock().await.unwrap() = Some(token);
+                client.write_token_cache().await?;
+                return;
+            }
+            Ok(None) => tracing::error!(
+                cache_path,
+                "cached spotify token expired and has no refresh token, log in again"
+            ),
+            Err(e) => tracing::error!(
+                cache_path,
+                "cached spotify token expired and could not be refreshed, log in again: {}",
+                e
+            ),
+        }
+        *client.get_token().lock().await.unwrap() = None;
+        if !std::io::stdin().is_terminal() {
+            throw!(eyre::eyre!(
+                "spotify token in {} can't be refreshed, log in again from a terminal",
+                cache_path
+            ));
+        }
+    }
+
+    let redirect = Url::parse(&client.get_oauth().redirect_uri)?;
+    let code = match bind(&redirect).await {
+        Ok(listener) => {
+            tracing::warn!(url, "log in to spotify");
+            tokio::time::timeout(LOGIN_TIMEOUT, wait_for_code(client, &redirect, listener))
+                .await
+                .map_err(|_| {
+                    eyre::eyre!(
+                        "nobody logged in to spotify within {}s",
+                        LOGIN_TIMEOUT.as_secs()
+                    )
+                })??
+        }
+        Err(e) if std::io::stdin().is_terminal() => {
+            tracing::warn!(
+                "can't listen on {}, falling back to prompt: {}",
+                redirect,
+                e
+            );
+            // This function requires the `cli` feature enabled.
+            let client = client.clone();
+            let url = url.to_string();
+            tokio::task::spawn_blocking(move || client.get_code_from_user(&url)).await??
+        }
+        Err(e) => throw!(eyre::eyre!(
+            "can't listen on {} for the spotify login, and there is no terminal to prompt on: {}",
+            redirect,
+            e
+        )),
+    };
+
+    client.request_token(&code).await?;
+    client.write_token_cache().await?;
+}
+
+#[throws(eyre::Error)]
+async fn bind(redirect: &Url) -> TcpListener {
+    let host = redirect.host_str().context("redirect_url has no host")?;
+    let port = redirect
+        .port_or_known_default()
+        .context("redirect_url has no port")?;
+    TcpListener::bind((host, port)).await?
+}
+
+/// Serve requests until one of them is spotify's redirect with the code in it.
+/// Anything else (a browser asking for favicon.ico) gets a 404.
+#[throws(eyre::Error)]
+async fn wait_for_code(client: &AuthCodeSpotify, redirect: &Url, listener: TcpListener) -> String {
+    loop {
+        let (mut stream, addr) = listener.accept().await?;
+        let mut buf = vec![0; 8192];
+        let mut len = 0;
+        while len < buf.len() && !buf[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+            match stream.read(&mut buf[len..]).await {
+                Ok(0) | Err(_) => break,
+                Ok(n) => len += n,
+            }
+        }
+
+        // GET /callback?code=...&state=... HTTP/1.1
+        let request = String::from_utf8_lossy(&buf[..len]);
+        let path = request.split_whitespace().nth(1).unwrap_or_default();
+        let Ok(url) = redirect.join(path) else {
+            continue;
+        };
+
+        if url.path() != redirect.path() {
+            stream
+                .write_all(b"HTTP/1.1 404 Not Found\r\nContent-Length: 0\r\n\r\n")
+                .await
+                .log_and_drop::<OnError>();
+            continue;
+        }
+
+        let denied = url
+            .query_pairs()
+            .find(|(k, _)| k == "error")
+            .map(|(_, v)| v.to_string());
+        let code = client.parse_response_code(url.as_str());
+        let body = match (&denied, &code) {
+            (Some(e), _) => format!("spotify login failed: {}", e),
+            (None, Some(_)) => "logged in to spotify, you can close this tab".to_string(),
+            (None, None) => "that doesn't look like a spotify login, try again".to_string(),
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        stream
+            .write_all(response.as_bytes())
+            .await
+            .log_and_drop::<OnError>();
+
+        match (denied, code) {
+            (Some(e), _) => throw!(eyre::eyre!("spotify login failed: {}", e)),
+            (None, Some(code)) => return code,
+            (None, None) => tracing::warn!(%addr, "callback without a valid code"),
+        }
+    }
+}
@@ -0,0 +1,655 @@
+//! A stand in for the spotify web api, so the module can be tested without spotify.
+//! Albums, tracks, artists and playlists live in memory, playlist edits are applied to them,
+//! and the next requests can be made to fail with a 429 or return `null` items.
+
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    sync::Arc,
+};
+
+use parking_lot::Mutex;
+use rspotify::Token;
+use serde_json::{json, Value};
+use tokio::net::TcpListener;
+use url::Url;
+
+use super::{
+    fixtures::{self, Replay, Request, Response},
+    init::user_scopes,
+    AuthFlow, Config,
+};
+
+/// album pages are this long, like spotify's
+const ALBUM_PAGE: usize = 50;
+/// playlist pages are this long, like spotify's
+const PLAYLIST_PAGE: usize = 100;
+
+pub struct MockSpotify {
+    url: String,
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    url: String,
+    albums: HashMap<String, Vec<String>>,
+    tracks: HashMap<String, Track>,
+    playlists: HashMap<String, Playlist>,
+    artists: HashMap<String, Artist>,
+    /// album -> its release date, `2024-01-01` if not set
+    release_dates: HashMap<String, String>,
+    /// the next requests get a 429 with these Retry-After seconds
+    rate_limits: VecDeque<u32>,
+    /// requests let through before `rate_limits` start
+    rate_limit_after: usize,
+    /// `METHOD /path?query` of every request, oldest first
+    log: Vec<String>,
+    /// answer the api from recorded fixtures instead
+    replay: Option<Replay>,
+    /// whether the request being handled asked for a market
+    market: bool,
+    /// track -> the version it is relinked to when asked for a market
+    relinks: HashMap<String, String>,
+    /// tracks which aren't playable when asked for a market
+    unplayable: HashSet<String>,
+    /// profile -> its public playlists
+    users: HashMap<String, Vec<String>>,
+    /// `type:id` of what the account saved or followed
+    library: Vec<String>,
+}
+
+struct Track {
+    album: String,
+    disc: u32,
+    number: u32,
+}
+
+/// everything is by `mockartist` as far as albums and tracks say, this is what the artist endpoints return
+struct Artist {
+    top_tracks: Vec<String>,
+    albums: Vec<String>,
+}
+
+struct Playlist {
+    description: String,
+    /// track ids, `None` is an item spotify returns as `null` (a removed track)
+    items: Vec<Option<String>>,
+    snapshot: u64,
+}
+
+impl MockSpotify {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let state = Arc::new(Mutex::new(State {
+            url: url.clone(),
+            ..Default::default()
+        }));
+
+        let s = state.clone();
+        let handler = move |req: Request| {
+            let response = s.lock().handle(&req.method, &req.target, req.body);
+            async move { response }
+        };
+        tokio::spawn(fixtures::serve(listener, handler));
+
+        MockSpotify { url, state }
+    }
+
+    /// A mock which answers the api with what was recorded in a `record_fixtures` file.
+    /// Logging in is still the mock's.
+    pub async fn replay(path: &str) -> Self {
+        let mock = Self::start().await;
+        let exchanges = fixtures::load(path).unwrap();
+        mock.state.lock().replay = Some(Replay::new(exchanges));
+        mock
+    }
+
+    /// Module config pointed at the mock.
+    /// Logging in as a user reads a token cache, which is written here so there is nothing to prompt for.
+    pub fn config(&self, flow: AuthFlow) -> Config {
+        let cache =
+            std::env::temp_dir().join(format!("goontunes-mock-{}.json", super::unique_id()));
+        let token = Token {
+            access_token: "mock".to_string(),
+            expires_in: chrono::Duration::hours(1),
+            expires_at: Some(chrono::Utc::now() + chrono::Duration::hours(1)),
+            refresh_token: None,
+            scopes: user_scopes(),
+        };
+        token.write_cache(&cache).unwrap();
+
+        Config {
+            id: "mock".to_string(),
+            secret: "mock".to_string(),
+            flow,
+            redirect_url: format!("{}callback", self.url),
+            token_cache_path: cache.to_string_lossy().to_string(),
+            refetch_days: None,
+            artist_expand: Default::default(),
+            user_playlists: false,
+            sync_retries: 2,
+            rate_limit: Default::default(),
+            market: None,
+            api_base_url: Some(format!("{}v1/", self.url)),
+            auth_base_url: Some(self.url.clone()),
+            record_fixtures: None,
+        }
+    }
+
+    /// an album with `n` tracks, returns the track ids
+    pub fn add_album(&self, id: &str, n: usize) -> Vec<String> {
+        let mut state = self.state.lock();
+        let tracks = (0..n).map(|i| format!("{}t{}", id, i)).collect::<Vec<_>>();
+        for (i, t) in tracks.iter().enumerate() {
+            let track = Track {
+                album: id.to_string(),
+                disc: 1,
+                number: i as u32 + 1,
+            };
+            state.tracks.insert(t.clone(), track);
+        }
+        state.albums.insert(id.to_string(), tracks.clone());
+        tracks
+    }
+
+    pub fn add_playlist(&self, id: &str, items: Vec<Option<String>>) {
+        let pl = Playlist {
+            description: String::new(),
+            items,
+            snapshot: 0,
+        };
+        self.state.lock().playlists.insert(id.to_string(), pl);
+    }
+
+    /// an artist with these top tracks and `(album, release date)`s, the albums need adding too
+    pub fn add_artist(&self, id: &str, top_tracks: &[String], albums: &[(&str, &str)]) {
+        let mut state = self.state.lock();
+        for (album, date) in albums {
+            state
+                .release_dates
+                .insert(album.to_string(), date.to_string());
+        }
+        let artist = Artist {
+            top_tracks: top_tracks.to_vec(),
+            albums: albums.iter().map(|(a, _)| a.to_string()).collect(),
+        };
+        state.artists.insert(id.to_string(), artist);
+    }
+
+    /// a profile with these public playlists
+    pub fn add_user(&self, id: &str, playlists: &[&str]) {
+        let playlists = playlists.iter().map(|p| p.to_string()).collect();
+        self.state.lock().users.insert(id.to_string(), playlists);
+    }
+
+    /// the playlist as it is now, `None` for null items
+    pub fn playlist(&self, id: &str) -> Vec<Option<String>> {
+        self.state.lock().playlists[id].items.clone()
+    }
+
+    /// with a market, `id` comes back as `playable` with `linked_from` set, like spotify's relinking
+    pub fn relink(&self, id: &str, playable: &str) {
+        let mut state = self.state.lock();
+        state.relinks.insert(id.to_string(), playable.to_string());
+    }
+
+    /// with a market, the track comes back as not playable
+    pub fn unplayable(&self, id: &str) {
+        self.state.lock().unplayable.insert(id.to_string());
+    }
+
+    /// the next requests to the api get a 429, with these Retry-After seconds
+    pub fn rate_limit(&self, retry_after: &[u32]) {
+        self.state.lock().rate_limits.extend(retry_after);
+    }
+
+    /// like [`MockSpotify::rate_limit`], after letting `n` requests through
+    pub fn rate_limit_after(&self, n: usize, retry_after: &[u32]) {
+        let mut state = self.state.lock();
+        state.rate_limit_after = n;
+        state.rate_limits.extend(retry_after);
+    }
+
+    /// `type:id` of what the account saved or followed, in order
+    pub fn library(&self) -> Vec<String> {
+        self.state.lock().library.clone()
+    }
+
+    /// `METHOD /path?query` of every request so far
+    pub fn requests(&self) -> Vec<String> {
+        self.state.lock().log.clone()
+    }
+}
+
+impl State {
+    fn handle(&mut self, method: &str, target: &str, body: Value) -> Response {
+        self.log.push(format!("{} {}", method, target));
+
+        let url = Url::parse("http://mock").unwrap().join(target).unwrap();
+        let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        let num =
+            |k: &str, default: usize| query.get(k).and_then(|v| v.parse().ok()).unwrap_or(default);
+        let ids = || {
+            query
+                .get("ids")
+                .map(|v| v.split(',').map(str::to_string).collect::<Vec<_>>())
+                .unwrap_or_default()
+        };
+        let path = url.path().trim_matches('/').split('/').collect::<Vec<_>>();
+        self.market = query.contains_key("market");
+
+        if path.first() == Some(&"v1") {
+            if !self.rate_limits.is_empty() && self.rate_limit_after > 0 {
+                self.rate_limit_after -= 1;
+            } else if let Some(secs) = self.rate_limits.pop_front() {
+                return Response {
+                    status: 429,
+                    headers: vec![("Retry-After".to_string(), secs.to_string())],
+                    body: String::new(),
+                };
+            }
+            if let Some(replay) = &mut self.replay {
+                return (replay.answer(method, target)).unwrap_or(Response::status(404));
+            }
+        }
+
+        match (method, path.as_slice()) {
+            ("POST", ["api", "token"]) => Response::ok(json!({
+                "access_token": "mock",
+                "token_type": "Bearer",
+                "expires_in": 3600,
+                "scope": "",
+            })),
+            ("GET", ["v1", "me"]) => Response::ok(self.user("mock")),
+            ("PUT", ["v1", "me", kind @ ("tracks" | "albums" | "following")]) => {
+                let kind = match *kind {
+                    "tracks" => "track",
+                    "albums" => "album",
+                    _ => "artist",
+                };
+                let saved = ids().into_iter().map(|id| format!("{}:{}", kind, id));
+                self.library.extend(saved);
+                Response::status(200)
+            }
+            ("GET", ["v1", "users", id]) => match self.users.contains_key(*id) {
+                true => Response::ok(self.user(id)),
+                false => Response::status(404),
+            },
+            ("GET", ["v1", "users", id, "playlists"]) => {
+                let Some(playlists) = self.users.get(*id) else {
+                    return Response::status(404);
+                };
+                let items = playlists.iter().map(|p| self.simple_playlist(p)).collect();
+                let href = format!("{}v1/users/{}/playlists", self.url, id);
+                Response::ok(page(&href, items, num("offset", 0), num("limit", 20)))
+            }
+
+            ("GET", ["v1", "albums"]) => {
+                let albums = ids().iter().map(|id| self.album(id)).collect::<Vec<_>>();
+                Response::ok(json!({ "albums": albums }))
+            }
+            ("GET", ["v1", "albums", id]) => match self.album(id) {
+                Value::Null => Response::status(404),
+                album => Response::ok(album),
+            },
+            ("GET", ["v1", "albums", id, "tracks"]) => {
+                let Some(tracks) = self.albums.get(*id) else {
+                    return Response::status(404);
+                };
+                let items = tracks.iter().map(|t| self.simple_track(t)).collect();
+                let href = format!("{}v1/albums/{}/tracks", self.url, id);
+                Response::ok(page(&href, items, num("offset", 0), num("limit", 20)))
+            }
+
+            ("GET", ["v1", "tracks"]) => {
+                let tracks = ids().iter().map(|id| self.track(id)).collect::<Vec<_>>();
+                Response::ok(json!({ "tracks": tracks }))
+            }
+            ("GET", ["v1", "tracks", id]) => match self.track(id) {
+                Value::Null => Response::status(404),
+                track => Response::ok(track),
+            },
+
+            ("GET", ["v1", "artists"]) => {
+                let artists = ids()
+                    .iter()
+                    .map(|id| self.full_artist(id))
+                    .collect::<Vec<_>>();
+                Response::ok(json!({ "artists": artists }))
+            }
+            ("GET", ["v1", "artists", id]) => match self.full_artist(id) {
+                Value::Null => Response::status(404),
+                artist => Response::ok(artist),
+            },
+            ("GET", ["v1", "artists", id, "top-tracks"]) => {
+                let Some(artist) = self.artists.get(*id) else {
+                    return Response::status(404);
+                };
+                let tracks = artist
+                    .top_tracks
+                    .iter()
+                    .map(|t| self.track(t))
+                    .collect::<Vec<_>>();
+                Response::ok(json!({ "tracks": tracks }))
+            }
+            ("GET", ["v1", "artists", id, "albums"]) => {
+                let Some(artist) = self.artists.get(*id) else {
+                    return Response::status(404);
+                };
+                let items = artist.albums.iter().map(|a| self.simple_album(a)).collect();
+                let href = format!("{}v1/artists/{}/albums", self.url, id);
+                Response::ok(page(&href, items, num("offset", 0), num("limit", 20)))
+            }
+
+            ("GET", ["v1", "playlists", id]) => match self.playlist(id) {
+                Value::Null => Response::status(404),
+                pl => Response::ok(pl),
+            },
+            ("GET", ["v1", "playlists", id, "tracks" | "items"]) => {
+                let Some(items) = self.playlist_items(id) else {
+                    return Response::status(404);
+                };
+                let href = format!("{}v1/playlists/{}/tracks", self.url, id);
+                Response::ok(page(&href, items, num("offset", 0), num("limit", 100)))
+            }
+            ("POST", ["v1", "playlists", id, "tracks" | "items"]) => {
+                self.edit_playlist(id, |items| {
+                    let uris = track_ids(&body["uris"])?;
+                    let at = body["position"].as_u64().map(|p| p as usize);
+                    let at = at.unwrap_or(items.len()).min(items.len());
+                    items.splice(at..at, uris.into_iter().map(Some));
+                    Some(())
+                })
+            }
+            ("PUT", ["v1", "playlists", id, "tracks" | "items"]) => {
+                self.edit_playlist(id, |items| {
+                    if !body["uris"].is_null() {
+                        *items = track_ids(&body["uris"])?.into_iter().map(Some).collect();
+                        return Some(());
+                    }
+                    let start = body["range_start"].as_u64()? as usize;
+                    let length = body["range_length"].as_u64().unwrap_or(1) as usize;
+                    let before = body["insert_before"].as_u64()? as usize;
+                    if start + length > items.len() || before > items.len() {
+                        return None;
+                    }
+                    let moved = items.drain(start..start + length).collect::<Vec<_>>();
+                    let before = match before > start {
+                        true => before - length,
+                        false => before,
+                    };
+                    items.splice(before..before, moved);
+                    Some(())
+                })
+            }
+            ("DELETE", ["v1", "playlists", id, "tracks" | "items"]) => {
+                self.edit_playlist(id, |items| {
+                    let mut positions = Vec::new();
+                    let mut everywhere = Vec::new();
+                    for t in body["tracks"].as_array()? {
+                        let id = track_id(&t["uri"])?;
+                        match t["positions"].as_array() {
+                            Some(p) => {
+                                for p in p {
+                                    let p = p.as_u64()? as usize;
+                                    // spotify refuses deletes which don't match the playlist
+                                    if items.get(p)?.as_ref() != Some(&id) {
+                                        return None;
+                                    }
+                                    positions.push(p);
+                                }
+                            }
+                            None => everywhere.push(id),
+                        }
+                    }
+                    positions.sort_unstable();
+                    positions.dedup();
+                    for p in positions.into_iter().rev() {
+                        items.remove(p);
+                    }
+                    items.retain(|t| !matches!(t, Some(t) if everywhere.contains(t)));
+                    Some(())
+                })
+            }
+            ("PUT", ["v1", "playlists", id]) => {
+                let Some(pl) = self.playlists.get_mut(*id) else {
+                    return Response::status(404);
+                };
+                if let Some(d) = body["description"].as_str() {
+                    pl.description = d.to_string();
+                }
+                Response::status(200)
+            }
+
+            _ => Response::status(404),
+        }
+    }
+
+    /// apply an edit, `None` from the edit is a bad request and leaves the playlist alone
+    fn edit_playlist(
+        &mut self,
+        id: &str,
+        edit: impl FnOnce(&mut Vec<Option<String>>) -> Option<()>,
+    ) -> Response {
+        let Some(pl) = self.playlists.get_mut(id) else {
+            return Response::status(404);
+        };
+        let mut items = pl.items.clone();
+        if edit(&mut items).is_none() {
+            return Response::status(400);
+        }
+        pl.items = items;
+        pl.snapshot += 1;
+        Response {
+            status: 201,
+            ..Response::ok(json!({ "snapshot_id": pl.snapshot.to_string() }))
+        }
+    }
+
+    fn user(&self, id: &str) -> Value {
+        json!({
+            "display_name": format!("user {}", id),
+            "external_urls": {},
+            "followers": { "href": null, "total": 0 },
+            "href": format!("{}v1/users/{}", self.url, id),
+            "id": id,
+            "images": [],
+            "type": "user",
+            "uri": format!("spotify:user:{}", id),
+        })
+    }
+
+    fn artist(&self) -> Value {
+        json!({
+            "external_urls": {},
+            "href": format!("{}v1/artists/mockartist", self.url),
+            "id": "mockartist",
+            "name": "mock artist",
+            "type": "artist",
+            "uri": "spotify:artist:mockartist",
+        })
+    }
+
+    fn full_artist(&self, id: &str) -> Value {
+        if !self.artists.contains_key(id) {
+            return Value::Null;
+        }
+        json!({
+            "external_urls": {},
+            "followers": { "href": null, "total": 0 },
+            "genres": [],
+            "href": format!("{}v1/artists/{}", self.url, id),
+            "id": id,
+            "images": [],
+            "name": format!("artist {}", id),
+            "popularity": 0,
+            "type": "artist",
+            "uri": format!("spotify:artist:{}", id),
+        })
+    }
+
+    fn simple_album(&self, id: &str) -> Value {
+        json!({
+            "album_type": "album",
+            "artists": [self.artist()],
+            "available_markets": [],
+            "external_urls": {},
+            "href": format!("{}v1/albums/{}", self.url, id),
+            "id": id,
+            "images": [],
+            "name": format!("album {}", id),
+            "release_date": self.release_dates.get(id).map_or("2024-01-01", |d| d.as_str()),
+            "release_date_precision": "day",
+            "total_tracks": self.albums.get(id).map_or(0, |t| t.len()),
+            "type": "album",
+            "uri": format!("spotify:album:{}", id),
+        })
+    }
+
+    /// with the first page of tracks, like spotify
+    fn album(&self, id: &str) -> Value {
+        let Some(tracks) = self.albums.get(id) else {
+            return Value::Null;
+        };
+        let items = tracks.iter().map(|t| self.simple_track(t)).collect();
+        let href = format!("{}v1/albums/{}/tracks", self.url, id);
+
+        let mut album = self.simple_album(id);
+        album["copyrights"] = json!([]);
+        album["external_ids"] = json!({});
+        album["genres"] = json!([]);
+        album["label"] = json!("mock");
+        album["popularity"] = json!(0);
+        album["tracks"] = page(&href, items, 0, ALBUM_PAGE);
+        album
+    }
+
+    fn simple_track(&self, id: &str) -> Value {
+        let Some(t) = self.tracks.get(id) else {
+            return Value::Null;
+        };
+        let mut track = json!({
+            "artists": [self.artist()],
+            "available_markets": [],
+            "disc_number": t.disc,
+            "duration_ms": 1000,
+            "explicit": false,
+            "external_urls": {},
+            "href": format!("{}v1/tracks/{}", self.url, id),
+            "id": id,
+            "is_local": false,
+            "name": format!("track {}", id),
+            "preview_url": null,
+            "track_number": t.number,
+            "type": "track",
+            "uri": format!("spotify:track:{}", id),
+        });
+        if !self.market {
+            return track;
+        }
+        match self.relinks.get(id) {
+            Some(playable) => {
+                track["linked_from"] = json!({
+                    "external_urls": {},
+                    "href": track["href"],
+                    "id": id,
+                    "type": "track",
+                    "uri": track["uri"],
+                });
+                track["id"] = json!(playable);
+                track["uri"] = json!(format!("spotify:track:{}", playable));
+                track["href"] = json!(format!("{}v1/tracks/{}", self.url, playable));
+                track["is_playable"] = json!(true);
+            }
+            None => track["is_playable"] = json!(!self.unplayable.contains(id)),
+        }
+        track
+    }
+
+    fn track(&self, id: &str) -> Value {
+        let Some(t) = self.tracks.get(id) else {
+            return Value::Null;
+        };
+        let mut track = self.simple_track(id);
+        track["album"] = self.simple_album(&t.album);
+        track["external_ids"] = json!({});
+        track["popularity"] = json!(0);
+        track
+    }
+
+    fn playlist_items(&self, id: &str) -> Option<Vec<Value>> {
+        let pl = self.playlists.get(id)?;
+        let items = pl.items.iter().map(|t| {
+            json!({
+                "added_at": "2024-01-01T00:00:00Z",
+                "added_by": null,
+                "is_local": false,
+                "track": t.as_ref().map_or(Value::Null, |t| self.track(t)),
+            })
+        });
+        Some(items.collect())
+    }
+
+    /// with the first page of items, like spotify
+    fn playlist(&self, id: &str) -> Value {
+        let (Some(pl), Some(items)) = (self.playlists.get(id), self.playlist_items(id)) else {
+            return Value::Null;
+        };
+        let href = format!("{}v1/playlists/{}/tracks", self.url, id);
+        json!({
+            "collaborative": false,
+            "description": pl.description,
+            "external_urls": {},
+            "followers": { "href": null, "total": 0 },
+            "href": format!("{}v1/playlists/{}", self.url, id),
+            "id": id,
+            "images": [],
+            "name": format!("playlist {}", id),
+            "owner": self.user("mock"),
+            "public": false,
+            "snapshot_id": pl.snapshot.to_string(),
+            "tracks": page(&href, items, 0, PLAYLIST_PAGE),
+            "type": "playlist",
+            "uri": format!("spotify:playlist:{}", id),
+        })
+    }
+
+    /// as listed on a profile, the items only as a count
+    fn simple_playlist(&self, id: &str) -> Value {
+        let mut pl = self.playlist(id);
+        let total = pl["tracks"]["total"].clone();
+        pl["tracks"] = json!({ "href": pl["tracks"]["href"], "total": total });
+        pl
+    }
+}
+
+/// `limit` items from `offset`, with `next` set when there are more
+fn page(href: &str, items: Vec<Value>, offset: usize, limit: usize) -> Value {
+    let total = items.len();
+    let next = (offset + limit < total)
+        .then(|| format!("{}?offset={}&limit={}", href, offset + limit, limit));
+    let items = items
+        .into_iter()
+        .skip(offset)
+        .take(limit)
+        .collect::<Vec<_>>();
+    json!({
+        "href": href,
+        "items": items,
+        "limit": limit,
+        "next": next,
+        "offset": offset,
+        "previous": null,
+        "total": total,
+    })
+}
+
+fn track_id(uri: &Value) -> Option<String> {
+    let id = uri.as_str()?.strip_prefix("spotify:track:")?;
+    Some(id.to_string())
+}
+
+fn track_ids(uris: &Value) -> Option<Vec<String>> {
+    uris.as_array()?.iter().map(track_id).collect()
+}
@@ -1,5 +1,5 @@
 use std::{
-    collections::{HashSet, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt::Debug,
     hash::Hash,
     ops::Deref,
@@ -10,10 +10,13 @@ use std::{
     time::Duration,
 };
 
-use culpa::throws;
+use culpa::{throw, throws};
 use eyre::Context;
-use fetcher::{depageinate_album, depageinate_playlist_fast};
-use futures::{FutureExt, SinkExt};
+use fetcher::{
+    depageinate_album, depageinate_playlist_fast, plan_sync, playlist_apply, stamp_description,
+    SyncOutcome,
+};
+use futures::{future::BoxFuture, FutureExt, SinkExt};
 use itertools::Itertools;
 use kameo::{
     actor::{ActorRef},
@@ -24,30 +27,81 @@ use parking_lot::{Mutex, RwLock};
 use rspotify::{
     http::HttpError,
     model::{
-        parse_uri, AlbumId, FullAlbum, FullPlaylist, FullTrack, Id,
-        PlayableItem, PlaylistId, TrackId, Type,
+        parse_uri, AlbumId, AlbumType, ArtistId, Country, FullAlbum, FullArtist, FullPlaylist,
+        FullTrack, Id, Market, PlayableId, PlayableItem, PlaylistId, PublicUser, TrackId, Type,
+        UserId,
     },
-    prelude::BaseClient,
-    AuthCodeSpotify, ClientError, ClientResult,
+    prelude::{BaseClient, OAuthClient},
+    ClientError, ClientResult,
 };
 use tokio::{
-    sync::{OwnedSemaphorePermit, Semaphore},
+    sync::{oneshot, OwnedSemaphorePermit, Semaphore},
     time::Instant,
 };
 use tracing::instrument;
 
+use crate::{
+    prelude::{Loggable, MyDb},
+    utils::{
+        diff::{full_replace, Actions},
+        when_even::OnError,
+    },
+};
 
 mod db;
+pub use db::{failed_fetches, FetchError};
 mod fetcher;
+mod fixtures;
 mod init;
+use init::Client;
+#[cfg(test)]
+mod mock;
+#[cfg(test)]
+mod tests;
 
 const MAX_ALBUMS: usize = 20;
 const MAX_TRACKS: usize = 100;
+const MAX_ARTISTS: usize = 50;
+
+/// connections bulk requests leave free, so interactive ones don't wait behind a backfill
+const RESERVED: usize = 1;
 
 pub enum ReqTypes {
     Album,
     Track,
+    Artist,
     Playlist,
+    User,
+}
+
+/// Interactive requests have someone waiting on them (`--get`, syncing, chat commands),
+/// bulk ones are backfilling links from chat. Interactive ones are scheduled first.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    #[default]
+    Bulk,
+    Interactive,
+}
+
+/// what [`Module::priotity`] picked for a free connection
+enum Next {
+    /// hand it to a request already underway, index into `Module::leases`
+    Lease(usize),
+    /// start a new request from the queue
+    Fetch(ReqTypes, Priority),
 }
 
 //TODO error on empty string
@@ -55,21 +109,134 @@ pub enum ReqTypes {
 pub struct Config {
     pub id: String,
     pub secret: String,
-    pub redirect_url: String,
 
+    /// how to log in, client credentials don't need a user but can't change anything
+    #[serde(default)]
+    pub flow: AuthFlow,
+
+    /// only needed for `auth_code`
+    #[serde(default)]
+    pub redirect_url: String,
+    /// only needed for `auth_code`
+    #[serde(default)]
     pub token_cache_path: String,
+
+    /// days before albums and tracks already in the db are fetched again, never if unset
+    #[serde(default)]
+    pub refetch_days: Option<u64>,
+
+    /// what else to fetch for shared artists, so playlists can turn them into tracks
+    #[serde(default)]
+    pub artist_expand: ArtistExpand,
+
+    /// fetch the public playlists of shared profiles too
+    #[serde(default)]
+    pub user_playlists: bool,
+
+    /// fresh plans for a playlist that still differs after syncing, before it is replaced wholesale
+    #[serde(default = "default_sync_retries")]
+    pub sync_retries: usize,
+
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+
+    /// country code (like `NL`) which albums and tracks are fetched for, so spotify says whether
+    /// they play there and relinks them to a version that does.
+    /// Unset is the logged in user's country, client credentials have none and get no relinking.
+    #[serde(default)]
+    pub market: Option<Country>,
+
+    /// somewhere other than spotify to send requests, like a proxy or the mock in the tests
+    #[serde(default)]
+    pub api_base_url: Option<String>,
+    #[serde(default)]
+    pub auth_base_url: Option<String>,
+
+    /// append every api request and spotify's answer to this file, to replay in tests
+    #[serde(default)]
+    pub record_fixtures: Option<String>,
+}
+
+/// how hard to push spotify, and what to do when it pushes back
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    /// requests in flight at once, at least one
+    #[serde(deserialize_with = "at_least_one")]
+    pub connections: usize,
+    /// attempts per request before it is given up on (and requeued)
+    pub max_tries: u32,
+    /// a Retry-After longer than this many seconds suspends fetching until it runs out,
+    /// instead of every request waiting it out while holding a connection
+    pub max_wait_secs: f32,
+}
+
+fn default_sync_retries() -> usize {
+    2
+}
+
+fn at_least_one<'de, D: serde::Deserializer<'de>>(d: D) -> Result<usize, D::Error> {
+    let n = <usize as serde::Deserialize>::deserialize(d)?;
+    if n == 0 {
+        return Err(serde::de::Error::custom("must be at least 1"));
+    }
+    Ok(n)
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        Self {
+            connections: 4,
+            max_tries: 8,
+            max_wait_secs: 30.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFlow {
+    /// log in as a user, who owns the playlists we write
+    #[default]
+    AuthCode,
+    /// read only, for instances which just fetch metadata
+    ClientCredentials,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ArtistExpand {
+    /// just the artist
+    #[default]
+    None,
+    /// the artist's top tracks, in the configured market (or the account's)
+    TopTracks,
+    /// the newest album or single
+    LatestRelease,
 }
 
 pub struct Module {
     config: Config,
-    client: Init<AuthCodeSpotify>,
+    client: Init<Client>,
+    db: MyDb,
 
     // TODO I was able to make queue not Arc, since I no longer spawn a task to consume them
     // This means I can also remove the mutexes from Queue implementation
     album_q: Queue<AlbumId<'static>>,
     track_q: Queue<TrackId<'static>>,
+    artist_q: Queue<ArtistId<'static>>,
+    playlist_q: Queue<PlaylistId<'static>>,
+    user_q: Queue<UserId<'static>>,
+
+    /// requests already underway waiting for a connection for their next page
+    leases: VecDeque<(Priority, oneshot::Sender<Lease>)>,
+
+    /// callers waiting on an id, by uri, see [`Module::wait_for`]
+    waiters: HashMap<String, Vec<oneshot::Sender<Result<SpotifyThing, String>>>>,
 
     ratelimiter: RateLimiter,
+    /// when the task is set to run again after a suspension, so it's only set once
+    wake_at: Option<Instant>,
 
     // TODO I'd prefer this to be pulled in from some kind of tokio task local variable
     this: ActorRef<Module>,
@@ -80,15 +247,132 @@ pub struct Module {
     trigger: TriggerTask<Task, Self>,
 }
 
-pub async fn init_and_spawn(config: Config) -> ActorRef<Module> {
+/// Fetch tracks and wait for them, one result per id in the same order.
+/// These go ahead of backfill, and share requests with anyone else asking for the same ids.
+#[throws(eyre::Report)]
+pub async fn get_tracks(
+    spotify: &ActorRef<Module>,
+    ids: &[String],
+) -> Vec<eyre::Result<FullTrack>> {
+    let uris: Vec<String> = ids
+        .iter()
+        .map(|id| Ok(TrackId::from_id_or_uri(id)?.uri()))
+        .collect::<eyre::Result<_>>()?;
+    get_things(spotify, uris, |t| match t {
+        SpotifyThing::Track(t) => Some(t),
+        _ => None,
+    })
+    .await?
+}
+
+/// like [`get_tracks`], for albums. the tracks are all there, not just the first page
+#[throws(eyre::Report)]
+pub async fn get_albums(
+    spotify: &ActorRef<Module>,
+    ids: &[String],
+) -> Vec<eyre::Result<FullAlbum>> {
+    let uris: Vec<String> = ids
+        .iter()
+        .map(|id| Ok(AlbumId::from_id_or_uri(id)?.uri()))
+        .collect::<eyre::Result<_>>()?;
+    get_things(spotify, uris, |t| match t {
+        SpotifyThing::Album(a) => Some(a),
+        _ => None,
+    })
+    .await?
+}
+
+/// like [`get_tracks`], for artists
+#[throws(eyre::Report)]
+pub async fn get_artists(
+    spotify: &ActorRef<Module>,
+    ids: &[String],
+) -> Vec<eyre::Result<FullArtist>> {
+    let uris: Vec<String> = ids
+        .iter()
+        .map(|id| Ok(ArtistId::from_id_or_uri(id)?.uri()))
+        .collect::<eyre::Result<_>>()?;
+    get_things(spotify, uris, |t| match t {
+        SpotifyThing::Artist(a, _) => Some(a),
+        _ => None,
+    })
+    .await?
+}
+
+/// create a playlist on the logged in account, returns the new playlist id
+#[throws(eyre::Report)]
+pub async fn create_playlist(
+    spotify: &ActorRef<Module>,
+    name: String,
+    description: Option<String>,
+) -> String {
+    let rx = spotify
+        .ask(CreatePlaylist { name, description })
+        .await
+        .map_err(|e| eyre::eyre!("create playlist: {:?}", e))?;
+    rx.await
+        .map_err(|_| eyre::eyre!("create playlist: spotify module went away"))??
+}
+
+/// the changes [`Module::sync_playlist`] would make, without touching the playlist
+#[throws(eyre::Report)]
+pub async fn plan_playlist_sync(
+    spotify: &ActorRef<Module>,
+    id: String,
+    tracks: Vec<TrackId<'static>>,
+    no_delete: bool,
+) -> Vec<Actions<PlayableId<'static>>> {
+    let rx = spotify
+        .ask(PlanPlaylistSync {
+            id,
+            tracks,
+            no_delete,
+        })
+        .await
+        .map_err(|e| eyre::eyre!("plan playlist sync: {:?}", e))?;
+    rx.await
+        .map_err(|_| eyre::eyre!("plan playlist sync: spotify module went away"))??
+}
+
+#[throws(eyre::Report)]
+async fn get_things<T>(
+    spotify: &ActorRef<Module>,
+    uris: Vec<String>,
+    unwrap: impl Fn(SpotifyThing) -> Option<T>,
+) -> Vec<eyre::Result<T>> {
+    let waiting = spotify
+        .ask(WaitFor { ids: uris.clone() })
+        .await
+        .map_err(|e| eyre::eyre!("fetch: {:?}", e))?;
+
+    let mut out = Vec::new();
+    for (uri, rx) in uris.into_iter().zip(waiting) {
+        let ret = match rx.await {
+            Ok(Ok(thing)) => unwrap(thing).ok_or_else(|| eyre::eyre!("{}: wrong kind", uri)),
+            Ok(Err(reason)) => Err(eyre::eyre!("{}: {}", uri, reason)),
+            Err(_) => Err(eyre::eyre!("{}: spotify module went away", uri)),
+        };
+        out.push(ret);
+    }
+    out
+}
+
+pub async fn init_and_spawn(config: Config, db: MyDb) -> ActorRef<Module> {
     kameo::actor::spawn_with(|actor_ref| async move {
         Module {
+            ratelimiter: RateLimiter::new(config.rate_limit.clone()),
+            wake_at: None,
             config,
             client: Default::default(),
+            db,
             this: actor_ref.clone(),
             album_q: Default::default(),
             track_q: Default::default(),
-            ratelimiter: Default::default(),
+            artist_q: Default::default(),
+            playlist_q: Default::default(),
+            user_q: Default::default(),
+            leases: Default::default(),
+            waiters: Default::default(),
             trigger: TriggerTask::new(actor_ref, Task),
         }
     })
@@ -103,10 +387,18 @@ impl kameo::Actor for Module {
     async fn on_start(&mut self, _: ActorRef<Self>) {
         // TODO there should be a pre start method allowing the creation of the Actor to be defered to after the creation of the ActorRef
 
+        if let Some(path) = self.config.record_fixtures.clone() {
+            let upstream = (self.config.api_base_url.clone())
+                .unwrap_or_else(|| fixtures::SPOTIFY_API.to_string());
+            self.config.api_base_url = Some(fixtures::record(path, upstream).await?);
+        }
+
         let client = init::connect(&self.config).await?;
         self.client.set(client);
         tracing::info!("SPOTIFY LOADED");
 
+        self.resume_pending().await.log_and_drop::<OnError>();
+
         // let q = self.album_q.clone();
         // let base = self.new_request(None);
         // tokio::spawn(async move {
@@ -129,113 +421,384 @@ impl kameo::Actor for Module {
 #[kameo::messages]
 impl Module {
     #[message]
-    pub fn fetch_thing(&mut self, id: String) {
+    pub async fn fetch_thing(&mut self, id: String) {
+        let priority = Priority::Interactive;
         match parse_uri(&id).context(id.clone()).unwrap().0 {
             Type::Album => {
-                self.fetch_album(vec![id]);
+                self.fetch_album(vec![id], priority).await;
             }
             Type::Track => {
-                self.fetch_track(vec![id]);
+                self.fetch_track(vec![id], priority).await;
+            }
+            Type::Artist => {
+                self.fetch_artist(vec![id], priority).await;
             }
             Type::Playlist => {
-                self.fetch_playlist(id);
+                self.queue_playlist(id, priority).await;
+            }
+            Type::User => {
+                self.queue_user(id, priority).await;
             }
             a => tracing::warn!(id = id, "{}: not implemented", a),
         }
     }
 
+    /// fetch a batch of links, skipping albums and tracks which are already in the db.
+    /// playlists and profiles change, so they are always fetched.
+    /// this is backfill, so it goes behind interactive requests.
     #[message]
-    pub fn fetch_album(&mut self, ids: Vec<String>) {
+    pub async fn fetch_things(&mut self, ids: Vec<String>) {
+        self.queue_unfetched(ids, Priority::Bulk).await;
+    }
+
+    #[message]
+    pub async fn fetch_album(&mut self, ids: Vec<String>, priority: Priority) {
         let ids = ids
             .into_iter()
-            .map(|s| AlbumId::from_id_or_uri(&s).unwrap().clone_static());
+            .map(|s| AlbumId::from_id_or_uri(&s).unwrap().clone_static())
+            .collect_vec();
 
-        self.album_q.add_unique(ids);
+        self.add_pending(ids.iter().map(Id::uri).collect(), priority)
+            .await;
+        self.album_q.add_unique(ids, priority);
         self.trigger.trigger_task();
     }
 
     #[message]
-    pub fn fetch_track(&mut self, ids: Vec<String>) {
+    pub async fn fetch_track(&mut self, ids: Vec<String>, priority: Priority) {
         let ids = ids
             .into_iter()
-            .map(|s| TrackId::from_id_or_uri(&s).unwrap().clone_static());
+            .map(|s| TrackId::from_id_or_uri(&s).unwrap().clone_static())
+            .collect_vec();
 
-        self.track_q.add_unique(ids);
+        self.add_pending(ids.iter().map(Id::uri).collect(), priority)
+            .await;
+        self.track_q.add_unique(ids, priority);
         self.trigger.trigger_task();
     }
 
     #[message]
-    pub fn fetch_playlist(&mut self, id: String) {
-        // TODO may still want a request queue, so that we can decide priority
-        dbg!(); // TODO what to log here? (universal log adaptor for actors??)
-        let id = PlaylistId::from_id_or_uri(&id).unwrap().clone_static();
-        tokio::spawn(self.new_request(None).playlist(id, None));
+    pub async fn fetch_artist(&mut self, ids: Vec<String>, priority: Priority) {
+        let ids = ids
+            .into_iter()
+            .map(|s| ArtistId::from_id_or_uri(&s).unwrap().clone_static())
+            .collect_vec();
+
+        self.add_pending(ids.iter().map(Id::uri).collect(), priority)
+            .await;
+        self.artist_q.add_unique(ids, priority);
+        self.trigger.trigger_task();
+    }
+
+    #[message]
+    pub async fn fetch_playlist(&mut self, id: String) {
+        self.queue_playlist(id, Priority::Interactive).await;
+    }
+
+    /// Queue the ids like [`Module::fetch_thing`], and hand back a receiver per id for its result.
+    /// Ids which are already queued or in flight aren't fetched twice, everyone waiting gets the one result.
+    #[message(derive(Debug))]
+    async fn wait_for(
+        &mut self,
+        ids: Vec<String>,
+    ) -> eyre::Result<Vec<oneshot::Receiver<Result<SpotifyThing, String>>>> {
+        let mut albums = Vec::new();
+        let mut tracks = Vec::new();
+        let mut artists = Vec::new();
+        for id in &ids {
+            // the typed ids check the id itself too, fetch_* would panic on a bad one
+            match parse_uri(id)?.0 {
+                Type::Album => albums.push(AlbumId::from_uri(id)?.uri()),
+                Type::Track => tracks.push(TrackId::from_uri(id)?.uri()),
+                Type::Artist => artists.push(ArtistId::from_uri(id)?.uri()),
+                a => eyre::bail!("{}: can't wait for a {}", id, a),
+            }
+        }
+
+        // only once they all parsed, a bad id mustn't leave waiters behind that nothing answers
+        let mut waiting = Vec::new();
+        for id in ids {
+            let (tx, rx) = oneshot::channel();
+            self.waiters.entry(id).or_default().push(tx);
+            waiting.push(rx);
+        }
+
+        let priority = Priority::Interactive;
+        if !albums.is_empty() {
+            self.fetch_album(albums, priority).await;
+        }
+        if !tracks.is_empty() {
+            self.fetch_track(tracks, priority).await;
+        }
+        if !artists.is_empty() {
+            self.fetch_artist(artists, priority).await;
+        }
+        Ok(waiting)
+    }
+
+    /// hand results to whoever is waiting on them, see [`Module::wait_for`]
+    fn answer(&mut self, data: &[SpotifyThing], failed: &[(String, String)]) {
+        if self.waiters.is_empty() {
+            return;
+        }
+        for d in data {
+            let Some(waiters) = d.uri().and_then(|uri| self.waiters.remove(&uri)) else {
+                continue;
+            };
+            for tx in waiters {
+                // the caller may have given up, that's fine
+                let _ = tx.send(Ok(d.clone()));
+            }
+        }
+        for (uri, reason) in failed {
+            for tx in self.waiters.remove(uri).into_iter().flatten() {
+                let _ = tx.send(Err(reason.clone()));
+            }
+        }
+    }
+
+    async fn queue_playlist(&mut self, id: String, priority: Priority) {
+        let Ok(id) = PlaylistId::from_id_or_uri(&id).log::<OnError>() else {
+            return;
+        };
+        self.add_pending(vec![id.uri()], priority).await;
+        self.playlist_q.add_unique([id.clone_static()], priority);
+        self.trigger.trigger_task();
+    }
+
+    async fn queue_user(&mut self, id: String, priority: Priority) {
+        let Ok(id) = UserId::from_id_or_uri(&id).log::<OnError>() else {
+            return;
+        };
+        self.add_pending(vec![id.uri()], priority).await;
+        self.user_q.add_unique([id.clone_static()], priority);
+        self.trigger.trigger_task();
+    }
+
+    /// Create a playlist on the logged in account, the receiver gets the new playlist id.
+    /// The request waits for a lease from this actor, so it can't be awaited in the handler,
+    /// see [`create_playlist`].
+    #[message(derive(Debug))]
+    fn create_playlist(
+        &mut self,
+        name: String,
+        description: Option<String>,
+    ) -> eyre::Result<oneshot::Receiver<eyre::Result<String>>> {
+        let conn = self.new_request(None, Priority::Interactive);
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            // the caller may have given up, that's fine
+            let _ = tx.send(conn.create_playlist(name, description).await);
+        });
+        Ok(rx)
+    }
+
+    /// make the spotify playlist match the given tracks
+    /// with `no_delete` new tracks are prepended and nothing is removed
+    #[message]
+    pub fn sync_playlist(&mut self, id: String, tracks: Vec<TrackId<'static>>, no_delete: bool) {
+        let Ok(id) = PlaylistId::from_id_or_uri(&id).log::<OnError>() else {
+            return;
+        };
+        tokio::spawn(self.new_request(None, Priority::Bulk).playlist_sync(
+            id.clone_static(),
+            tracks,
+            no_delete,
+            self.config.sync_retries,
+        ));
+    }
+
+    /// Save shared tracks and albums to the account's library, and with `follow` also follow
+    /// shared artists and the artists of the rest. Anything saved before is skipped.
+    #[message]
+    pub fn save_to_library(&mut self, ids: Vec<String>, follow: bool) {
+        let conn = self.new_request(None, Priority::Bulk);
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            conn.save_to_library(db, ids, follow)
+                .await
+                .log_and_drop::<OnError>()
+        });
+    }
+
+    /// The changes sync_playlist would make, without touching the playlist.
+    /// Like [`Module::create_playlist`] the receiver gets the result, see [`plan_playlist_sync`].
+    #[message(derive(Debug))]
+    fn plan_playlist_sync(
+        &mut self,
+        id: String,
+        tracks: Vec<TrackId<'static>>,
+        no_delete: bool,
+    ) -> eyre::Result<oneshot::Receiver<eyre::Result<Vec<Actions<PlayableId<'static>>>>>> {
+        let id = PlaylistId::from_id_or_uri(&id)?.clone_static();
+        let conn = self.new_request(None, Priority::Interactive);
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let _ = tx.send(conn.playlist_plan(id, tracks, no_delete).await);
+        });
+        Ok(rx)
     }
 
     #[message(derive(Clone))]
     pub fn task(&mut self) {
         self.trigger.reset();
+        self.ratelimiter.resume_if_due();
+        if let Some(until) = self.ratelimiter.suspended_until() {
+            // nothing goes out until spotify lets us, come back then
+            if self.wake_at != Some(until) {
+                self.wake_at = Some(until);
+                let trigger = self.trigger.clone();
+                tokio::spawn(async move {
+                    tokio::time::sleep_until(until).await;
+                    trigger.trigger_task();
+                });
+            }
+            return;
+        }
+
         while let Ok(c) = self.ratelimiter.connections.clone().try_acquire_owned() {
             // ALLOCATE A NEW CONNECTION
-            match self.priotity() {
-                Some(ReqTypes::Album) => {
-                    let conn = self.new_request(Some(c));
+            let free = self.ratelimiter.connections.available_permits();
+            let c = Lease::new(c, self.trigger.clone());
+            match self.priotity(free) {
+                Some(Next::Lease(i)) => {
+                    let (_, tx) = self.leases.remove(i).expect("priority gave a valid index");
+                    // if the request went away the lease is dropped, which runs us again
+                    let _ = tx.send(c);
+                }
+                Some(Next::Fetch(ReqTypes::Album, priority)) => {
+                    let conn = self.new_request(Some(c), priority);
                     let ids = self
                         .album_q
                         .take_unclaimed(conn.reqid, 1, MAX_ALBUMS)
                         .expect("priority wrong, nothing else should touch this");
                     tokio::spawn(conn.albums(ids));
                 }
-                Some(ReqTypes::Track) => {
-                    let conn = self.new_request(Some(c));
+                Some(Next::Fetch(ReqTypes::Track, priority)) => {
+                    let conn = self.new_request(Some(c), priority);
                     let ids = self
                         .track_q
                         .take_unclaimed(conn.reqid, 1, MAX_TRACKS)
                         .expect("priority wrong, nothing else should touch this");
                     tokio::spawn(conn.tracks(ids));
                 }
+                Some(Next::Fetch(ReqTypes::Artist, priority)) => {
+                    let conn = self.new_request(Some(c), priority);
+                    let ids = self
+                        .artist_q
+                        .take_unclaimed(conn.reqid, 1, MAX_ARTISTS)
+                        .expect("priority wrong, nothing else should touch this");
+                    tokio::spawn(conn.artists(ids, self.config.artist_expand));
+                }
+                Some(Next::Fetch(ReqTypes::Playlist, priority)) => {
+                    let conn = self.new_request(Some(c), priority);
+                    let id = self
+                        .playlist_q
+                        .take_unclaimed(conn.reqid, 1, 1)
+                        .expect("priority wrong, nothing else should touch this")
+                        .remove(0);
+                    tokio::spawn(conn.playlist(id, None));
+                }
+                Some(Next::Fetch(ReqTypes::User, priority)) => {
+                    let conn = self.new_request(Some(c), priority);
+                    let id = self
+                        .user_q
+                        .take_unclaimed(conn.reqid, 1, 1)
+                        .expect("priority wrong, nothing else should touch this")
+                        .remove(0);
+                    tokio::spawn(conn.user(id, self.config.user_playlists));
+                }
                 None => {
+                    // the lease goes back without waking us, there is nothing to do
+                    c.forget_trigger();
                     tracing::info!("Idle");
                     break;
                 }
-                _ => {
-                    todo!();
-                }
             };
         }
     }
 
-    pub fn priotity(&mut self) -> Option<ReqTypes> {
-        // TODO
-        // With this method how to prioritize?
-        // reserve one connection for each request type
-        // then prioritze playlists
-        // then prioritze full batches
-        // then prioritze
-        let ready = self.album_q.ready();
-        if ready > 0 && self.album_q.in_flight() == 0 || ready >= MAX_ALBUMS {
-            return Some(ReqTypes::Album);
-        }
+    /// What to do with a free connection, `free` is how many are left after it.
+    /// - interactive requests before bulk ones
+    /// - pages of requests already underway before new requests, since they hold up the rest of theirs
+    /// - playlists and profiles, then albums, tracks and artists
+    /// - bulk batches which aren't full only go out while nothing else of their type is in flight,
+    ///   that way every type gets a connection before any type gets two
+    /// - bulk never takes the last [`RESERVED`] connections, but always gets at least one
+    fn priotity(&mut self, free: usize) -> Option<Next> {
+        let reserved = RESERVED.min(self.config.rate_limit.connections.saturating_sub(1));
+        for priority in [Priority::Interactive, Priority::Bulk] {
+            if priority == Priority::Bulk && free < reserved {
+                return None;
+            }
 
-        let ready = self.track_q.ready();
-        if ready > 0 && self.track_q.in_flight() == 0 || ready >= MAX_TRACKS {
-            return Some(ReqTypes::Track);
+            if let Some(i) = self.leases.iter().position(|(p, _)| *p == priority) {
+                return Some(Next::Lease(i));
+            }
+
+            let interactive = priority == Priority::Interactive;
+            let go = |ready: usize, in_flight: usize, max: usize| {
+                ready > 0 && (interactive || in_flight == 0) || ready >= max
+            };
+
+            if self.playlist_q.ready(priority) > 0 {
+                return Some(Next::Fetch(ReqTypes::Playlist, priority));
+            }
+            if self.user_q.ready(priority) > 0 {
+                return Some(Next::Fetch(ReqTypes::User, priority));
+            }
+
+            let ready = self.album_q.ready(priority);
+            if go(ready, self.album_q.in_flight(), MAX_ALBUMS) {
+                return Some(Next::Fetch(ReqTypes::Album, priority));
+            }
+
+            let ready = self.track_q.ready(priority);
+            if go(ready, self.track_q.in_flight(), MAX_TRACKS) {
+                return Some(Next::Fetch(ReqTypes::Track, priority));
+            }
+
+            let ready = self.artist_q.ready(priority);
+            if go(ready, self.artist_q.in_flight(), MAX_ARTISTS) {
+                return Some(Next::Fetch(ReqTypes::Artist, priority));
+            }
         }
         None
     }
 
+    /// a request already underway wants a connection for its next page
+    #[message]
+    fn wait_lease(&mut self, priority: Priority, tx: oneshot::Sender<Lease>) {
+        self.leases.push_back((priority, tx));
+        self.trigger.trigger_task();
+    }
+
+    /// what the rate limiter is up to, for working out why fetching stalled
+    #[message(derive(Debug))]
+    pub fn rate_limit_state(&mut self) -> RateLimiterState {
+        let queued = self.album_q.ready(Priority::Bulk)
+            + self.track_q.ready(Priority::Bulk)
+            + self.artist_q.ready(Priority::Bulk)
+            + self.playlist_q.ready(Priority::Bulk)
+            + self.user_q.ready(Priority::Bulk);
+        self.ratelimiter.state(queued, self.leases.len())
+    }
+
     /// get data back from fetcher
     #[message]
-    async fn fetcher_data(&mut self, data: Vec<SpotifyThing>, reqid: Option<u64>) {
-        // TODO handle missing tracks
-        if let Some(reqid) = reqid {
-            self.album_q.remove(reqid);
-            self.track_q.remove(reqid); //XXX fixme
-        }
+    async fn fetcher_data(
+        &mut self,
+        data: Vec<SpotifyThing>,
+        mut failed: Vec<(String, String)>,
+        reqid: Option<u64>,
+    ) {
+        let requested = match reqid {
+            Some(reqid) => self.remove_request(reqid),
+            None => vec![],
+        };
         self.trigger.trigger_task();
 
-        // TODO do something with the data
-        for d in data {
+        for d in data.iter() {
             match d {
                 SpotifyThing::Album(full_album) => {
                     tracing::trace!(name = &full_album.name);
@@ -243,6 +806,9 @@ impl Module {
                 SpotifyThing::Track(full_track) => {
                     tracing::trace!(name = &full_track.name);
                 }
+                SpotifyThing::Artist(full_artist, _) => {
+                    tracing::trace!(name = &full_artist.name);
+                }
                 SpotifyThing::Playlist(full_playlist) => {
                     tracing::trace!(name = &full_playlist.name);
                     // let mut v = Vec::new();
@@ -256,8 +822,45 @@ impl Module {
                     // }
                     // self.fetch_album(v);
                 }
+                SpotifyThing::User(user, playlists) => {
+                    tracing::trace!(name = ?user.display_name);
+                    for pl in playlists.iter().flatten() {
+                        self.queue_playlist(pl.clone(), Priority::Bulk).await;
+                    }
+                }
             }
         }
+
+        // batch endpoints leave out ids they don't know
+        let returned: HashSet<String> = data.iter().filter_map(SpotifyThing::uri).collect();
+        let known: HashSet<String> = failed.iter().map(|(uri, _)| uri.clone()).collect();
+        failed.extend(
+            requested
+                .into_iter()
+                .filter(|uri| !returned.contains(uri) && !known.contains(uri))
+                .map(|uri| (uri, "not returned by spotify".to_string())),
+        );
+        self.answer(&data, &failed);
+        let done = (returned.into_iter())
+            .chain(failed.iter().map(|(uri, _)| uri.clone()))
+            .collect_vec();
+
+        // off the actor, so writing big playlists doesn't hold up the queues
+        let db = self.db.clone();
+        tokio::spawn(async move {
+            if !failed.is_empty() {
+                db::record_failed(&db, failed)
+                    .await
+                    .log_and_drop::<OnError>();
+            }
+            let unstored = db::add_things(&db, data).await;
+            // only now, so a restart before this point fetches them again
+            let done = done
+                .into_iter()
+                .filter(|uri| !unstored.contains(uri))
+                .collect_vec();
+            db::clear_pending(&db, done).await.log_and_drop::<OnError>();
+        });
     }
 
     /// get data back from fetcher
@@ -265,26 +868,185 @@ impl Module {
     fn fetcher_err(&mut self, err: ClientError, reqid: u64) {
         // TODO pass more request info, like kind
         match RateLimit::get(&err) {
-            Some(rl) => {
-                dbg!();
+            Some(_) => {
+                // back in the queue, it goes out again once the rate limit is over
+                tracing::warn!(reqid, "rate limited, requeueing");
                 self.album_q.release(reqid);
                 self.track_q.release(reqid);
+                self.artist_q.release(reqid);
+                self.playlist_q.release(reqid);
+                self.user_q.release(reqid);
             }
             None => {
                 tracing::error!("{}", err);
-                self.album_q.remove(reqid);
-                self.track_q.remove(reqid);
+                let failed = self
+                    .remove_request(reqid)
+                    .into_iter()
+                    .map(|uri| (uri, err.to_string()))
+                    .collect_vec();
+                self.answer(&[], &failed);
+                let db = self.db.clone();
+                tokio::spawn(async move {
+                    let done = failed.iter().map(|(uri, _)| uri.clone()).collect_vec();
+                    db::record_failed(&db, failed)
+                        .await
+                        .log_and_drop::<OnError>();
+                    db::clear_pending(&db, done).await.log_and_drop::<OnError>();
+                });
             }
         }
         self.trigger.trigger_task();
     }
 
-    fn new_request(&self, c: Option<OwnedSemaphorePermit>) -> Conn {
+    /// albums whose tracks were rate limited, back in the queue like in [`Module::fetcher_err`]
+    #[message]
+    fn requeue_albums(&mut self, ids: Vec<AlbumId<'static>>, reqid: u64) {
+        tracing::warn!(reqid, n = ids.len(), "album pages rate limited, requeueing");
+        self.album_q.release_some(reqid, &ids);
+        self.trigger.trigger_task();
+    }
+
+    /// take the request out of the queues, returns the uris it was for
+    fn remove_request(&self, reqid: u64) -> Vec<String> {
+        let albums = self.album_q.remove(reqid).into_iter().map(|r| r.id.uri());
+        let tracks = self.track_q.remove(reqid).into_iter().map(|r| r.id.uri());
+        let artists = self.artist_q.remove(reqid).into_iter().map(|r| r.id.uri());
+        let playlists = self
+            .playlist_q
+            .remove(reqid)
+            .into_iter()
+            .map(|r| r.id.uri());
+        let users = self.user_q.remove(reqid).into_iter().map(|r| r.id.uri());
+        albums
+            .chain(tracks)
+            .chain(artists)
+            .chain(playlists)
+            .chain(users)
+            .collect_vec()
+    }
+
+    /// Queue uris like [`Module::fetch_things`], skipping what is in the db already or failing.
+    /// Returns the uris which were left out.
+    async fn queue_unfetched(&mut self, ids: Vec<String>, priority: Priority) -> Vec<String> {
+        let mut albums = Vec::new();
+        let mut tracks = Vec::new();
+        let mut artists = Vec::new();
+        let mut skipped = Vec::new();
+        for id in ids.into_iter().unique() {
+            match parse_uri(&id).map(|(t, _)| t) {
+                Ok(Type::Album) => albums.push(id),
+                Ok(Type::Track) => tracks.push(id),
+                Ok(Type::Artist) => artists.push(id),
+                Ok(Type::Playlist) => self.queue_playlist(id, priority).await,
+                Ok(Type::User) => self.queue_user(id, priority).await,
+                Ok(a) => {
+                    tracing::warn!(id = id, "{}: not implemented", a);
+                    skipped.push(id);
+                }
+                Err(e) => {
+                    tracing::warn!(id = id, "{}", e);
+                    skipped.push(id);
+                }
+            }
+        }
+
+        let left = self.unfetched("album", albums.clone()).await;
+        skipped.extend(Self::left_out(albums, &left));
+        if !left.is_empty() {
+            self.fetch_album(left, priority).await;
+        }
+        let left = self.unfetched("track", tracks.clone()).await;
+        skipped.extend(Self::left_out(tracks, &left));
+        if !left.is_empty() {
+            self.fetch_track(left, priority).await;
+        }
+        let left = self.unfetched("artist", artists.clone()).await;
+        skipped.extend(Self::left_out(artists, &left));
+        if !left.is_empty() {
+            self.fetch_artist(left, priority).await;
+        }
+        skipped
+    }
+
+    /// of `all`, the ones which aren't in `kept`
+    fn left_out(all: Vec<String>, kept: &[String]) -> impl Iterator<Item = String> + '_ {
+        let kept: HashSet<&String> = kept.iter().collect();
+        all.into_iter().filter(move |id| !kept.contains(id))
+    }
+
+    /// Remember queued uris in the db until they have been fetched, see [`db::pending`].
+    /// Awaited before queueing, so the fetch can't finish (and clear them) before they are written.
+    async fn add_pending(&self, uris: Vec<String>, priority: Priority) {
+        db::add_pending(&self.db, uris, priority)
+            .await
+            .log_and_drop::<OnError>();
+    }
+
+    /// Queue again what was pending when we last stopped.
+    /// Anything which made it into the db (or failed) before that is dropped.
+    #[throws(eyre::Report)]
+    async fn resume_pending(&mut self) {
+        let pending = db::pending(&self.db).await?;
+        if pending.is_empty() {
+            return;
+        }
+        tracing::info!(
+            n = pending.len(),
+            "resuming fetches from before the restart"
+        );
+        let by_priority = pending
+            .into_iter()
+            .map(|(uri, priority)| (priority, uri))
+            .into_group_map();
+        for (priority, uris) in by_priority {
+            let skipped = self.queue_unfetched(uris, priority).await;
+            db::clear_pending(&self.db, skipped).await?;
+        }
+    }
+
+    /// the ids which aren't in the db yet, or were fetched more than `refetch_days` ago.
+    /// ids which failed recently are left out too, see [`db::record_failed`].
+    async fn unfetched(&self, table: &str, ids: Vec<String>) -> Vec<String> {
+        if ids.is_empty() {
+            return ids;
+        }
+        let max_age = self
+            .config
+            .refetch_days
+            .map(|d| chrono::Duration::days(d as i64));
+        let have = db::fetched(&self.db, table, &ids, max_age)
+            .await
+            .log::<OnError>()
+            .unwrap_or_default();
+        let dead = db::backing_off(&self.db, &ids)
+            .await
+            .log::<OnError>()
+            .unwrap_or_default();
+        let ids = ids
+            .into_iter()
+            .filter(|id| !have.contains(id) && !dead.contains(id))
+            .collect_vec();
+        tracing::info!(
+            table,
+            n = ids.len(),
+            skipped = have.len(),
+            backing_off = dead.len(),
+            "fetching"
+        );
+        ids
+    }
+
+    fn new_request(&self, c: Option<Lease>, priority: Priority) -> Conn {
+        let client = self.client.get().clone();
         Conn {
             actor_ref: self.this.clone(),
-            client: self.client.get().clone(),
+            market: (self.config.market)
+                .map(Market::Country)
+                .or_else(|| client.token_market()),
+            client,
             ratelimiter: self.ratelimiter.clone(),
             reqid: unique_id(),
+            priority,
             c,
         }
     }
@@ -292,10 +1054,12 @@ impl Module {
 
 struct Conn {
     actor_ref: ActorRef<Module>,
-    client: AuthCodeSpotify,
+    client: Client,
+    market: Option<Market>,
     ratelimiter: RateLimiter,
     reqid: u64,
-    c: Option<OwnedSemaphorePermit>,
+    priority: Priority,
+    c: Option<Lease>,
 }
 
 impl Conn {
@@ -305,29 +1069,60 @@ impl Conn {
         self.acquire().await;
 
         // TODO actually albums should cancel on rate limit.
-        let mut albums = self
+        let albums = self
             .ratelimiter
             .with_rate_limit(
-                || self.client.albums(ids.clone(), None),
+                || self.client.albums(ids.clone(), self.market),
                 ids.len() == MAX_ALBUMS, //XXX moveme, abort on rate limit if this is a partial batch
             )
-            .await
-            .unwrap();
+            .await;
+        let (mut albums, mut failed) = match albums {
+            Ok(albums) => (albums, vec![]),
+            Err(e) if RateLimit::get(&e).is_none() && ids.len() > 1 => {
+                tracing::warn!("batch failed, fetching one at a time: {}", e);
+                self.one_by_one(&ids, |id| self.client.album(id.clone(), self.market))
+                    .await
+            }
+            Err(e) => return self.send_error(e).await,
+        };
         self.c = None; // drop lease
 
-        // depaginate tracks
+        // depaginate tracks, each page waits for a connection from the module
         {
-            // Really this should get splintered off into seperate sub connections
+            let pager = self.pager();
             let mut counter = 0;
+            let mut incomplete = HashSet::new();
+            let mut limited = Vec::new();
             for a in albums.iter_mut() {
                 if a.tracks.total as usize > a.tracks.items.len() {
                     counter += 1;
-                    depageinate_album(&self.client, &self.ratelimiter, a)
-                        .await
-                        .unwrap();
+                    let ret =
+                        depageinate_album(&self.client, &self.ratelimiter, &pager, self.market, a)
+                            .await;
+                    if let Err(e) = ret {
+                        // half an album would make for a wrong tracklist
+                        incomplete.insert(a.id.clone());
+                        match e.downcast_ref::<ClientError>().and_then(RateLimit::get) {
+                            Some(_) => limited.push(a.id.clone()),
+                            None => failed.push((a.id.uri(), e.to_string())),
+                        }
+                    }
                 }
             }
-            tracing::info!("depaginate_done on {} albums", counter)
+            albums.retain(|a| !incomplete.contains(&a.id));
+            tracing::info!("depaginate_done on {} albums", counter);
+
+            if !limited.is_empty() {
+                // before the data, which takes the rest of the request out of the queue
+                self.actor_ref
+                    .tell(RequeueAlbums {
+                        ids: limited,
+                        reqid: self.reqid,
+                    })
+                    .send()
+                    .await
+                    .unwrap();
+            }
         }
 
         tracing::info!(n = albums.len(), "fetched albums");
@@ -335,7 +1130,7 @@ impl Conn {
             .into_iter()
             .map(|a| SpotifyThing::Album(a))
             .collect_vec();
-        self.return_data(data).await;
+        self.return_data(data, failed).await;
     }
 
     #[tracing::instrument(skip_all)]
@@ -345,11 +1140,19 @@ impl Conn {
         let tracks = self
             .ratelimiter
             .with_rate_limit(
-                || self.client.tracks(ids.clone(), None),
+                || self.client.tracks(ids.clone(), self.market),
                 ids.len() == MAX_TRACKS,
             )
-            .await
-            .unwrap();
+            .await;
+        let (tracks, failed) = match tracks {
+            Ok(tracks) => (tracks, vec![]),
+            Err(e) if RateLimit::get(&e).is_none() && ids.len() > 1 => {
+                tracing::warn!("batch failed, fetching one at a time: {}", e);
+                self.one_by_one(&ids, |id| self.client.track(id.clone(), self.market))
+                    .await
+            }
+            Err(e) => return self.send_error(e).await,
+        };
         self.c = None; // drop lease
 
         tracing::info!(n = tracks.len(), "fetched tracks");
@@ -358,63 +1161,451 @@ impl Conn {
             .map(|t| SpotifyThing::Track(t))
             .collect_vec();
 
-        self.return_data(data).await;
+        self.return_data(data, failed).await;
+    }
+
+    #[tracing::instrument(skip_all)]
+    async fn artists(mut self, ids: Vec<ArtistId<'static>>, expand: ArtistExpand) {
+        tracing::info!(count = ids.len());
+        self.acquire().await;
+        let artists = self
+            .ratelimiter
+            .with_rate_limit(
+                || self.client.artists(ids.clone()),
+                ids.len() == MAX_ARTISTS,
+            )
+            .await;
+        let (artists, failed) = match artists {
+            Ok(artists) => (artists, vec![]),
+            Err(e) if RateLimit::get(&e).is_none() && ids.len() > 1 => {
+                tracing::warn!("batch failed, fetching one at a time: {}", e);
+                self.one_by_one(&ids, |id| self.client.artist(id.clone()))
+                    .await
+            }
+            Err(e) => return self.send_error(e).await,
+        };
+        self.c = None; // drop lease
+
+        // one more request per artist, the endpoints don't take batches.
+        // each leases its own connection, like the pages of a request
+        let pager = self.pager();
+        let mut data = Vec::new();
+        let mut albums = Vec::new();
+        for artist in artists {
+            let mut extra = ArtistExpansion::default();
+            match expand {
+                ArtistExpand::None => {}
+                ArtistExpand::TopTracks => {
+                    let ret = pager
+                        .with_rate_limit(&self.ratelimiter, || {
+                            self.client
+                                .artist_top_tracks(artist.id.clone(), self.market)
+                        })
+                        .await;
+                    if let Ok(tracks) = ret.log::<OnError>() {
+                        let ids = tracks
+                            .iter()
+                            .filter_map(|t| Some(t.id.as_ref()?.to_string()));
+                        extra.top_tracks = Some(ids.collect());
+                        data.extend(tracks.into_iter().map(SpotifyThing::Track));
+                    }
+                }
+                ArtistExpand::LatestRelease => {
+                    let ret = pager
+                        .with_rate_limit(&self.ratelimiter, || {
+                            self.client.artist_albums_manual(
+                                artist.id.clone(),
+                                [AlbumType::Album, AlbumType::Single],
+                                self.market,
+                                Some(50),
+                                None,
+                            )
+                        })
+                        .await;
+                    if let Ok(page) = ret.log::<OnError>() {
+                        // release dates are iso (of varying precision), so the biggest is the newest
+                        let latest = page
+                            .items
+                            .into_iter()
+                            .filter_map(|a| Some((a.release_date?, a.id?)))
+                            .max_by(|a, b| a.0.cmp(&b.0));
+                        if let Some((_, id)) = latest {
+                            extra.latest_release = Some(id.to_string());
+                            albums.push(id.to_string());
+                        }
+                    }
+                }
+            }
+            data.push(SpotifyThing::Artist(artist, extra));
+        }
+
+        tracing::info!(n = data.len(), albums = albums.len(), "fetched artists");
+        if !albums.is_empty() {
+            self.actor_ref
+                .tell(FetchAlbum {
+                    ids: albums,
+                    priority: self.priority,
+                })
+                .send()
+                .await
+                .unwrap();
+        }
+        self.return_data(data, failed).await;
     }
 
     #[tracing::instrument(skip_all)]
     async fn playlist(mut self, id: PlaylistId<'static>, snapshot: Option<String>) {
         self.acquire().await;
-        let mut pl = self
+        let pl = self
             .ratelimiter
-            .with_rate_limit(|| self.client.playlist(id.clone(), None, None), true)
-            .await
-            .unwrap();
+            .with_rate_limit(|| self.client.playlist(id.clone(), None, self.market), true)
+            .await;
         self.c = None; // drop lease
+        let mut pl = match pl {
+            Ok(pl) => pl,
+            Err(e) => return self.send_error(e).await,
+        };
 
         if snapshot.as_ref() != Some(&pl.snapshot_id)
             && pl.tracks.total as usize > pl.tracks.items.len()
         {
-            depageinate_playlist_fast(&self.client, &self.ratelimiter, &mut pl, async |a| {
-                tracing::info!("page {} / {}", a.offset, a.total);
-            })
-            .await
-            .unwrap();
+            let ret = depageinate_playlist_fast(
+                &self.client,
+                &self.ratelimiter,
+                &self.pager(),
+                self.market,
+                &mut pl,
+                async |a| {
+                    tracing::info!("page {} / {}", a.offset, a.total);
+                },
+            )
+            .await;
+            if let Err(e) = ret {
+                let e = match e.downcast::<ClientError>() {
+                    // requeued, same as a rate limit on the first page
+                    Ok(e) if RateLimit::get(&e).is_some() => return self.send_error(e).await,
+                    Ok(e) => e.to_string(),
+                    Err(e) => e.to_string(),
+                };
+                tracing::error!("{}", e);
+                return self.return_data(vec![], vec![(id.uri(), e)]).await;
+            }
             tracing::info!("depaginating DONE");
         }
 
         tracing::info!(n = pl.tracks.items.len(), "fetched tracks");
 
         let data = SpotifyThing::Playlist(pl);
-        self.return_data(vec![data]).await;
+        self.return_data(vec![data], vec![]).await;
     }
 
-    #[tracing::instrument(skip_all)]
+    /// a profile, and the uris of its public playlists if `playlists` is set
+    #[tracing::instrument(skip(self))]
+    async fn user(mut self, id: UserId<'static>, playlists: bool) {
+        self.acquire().await;
+        let user = self
+            .ratelimiter
+            .with_rate_limit(|| self.client.user(id.clone()), true)
+            .await;
+        self.c = None; // drop lease
+        let user = match user {
+            Ok(user) => user,
+            Err(e) => return self.send_error(e).await,
+        };
+
+        let playlists = match playlists {
+            true => match self.user_playlists(&id).await {
+                Ok(p) => Some(p),
+                Err(e) => {
+                    // the profile is still worth having
+                    tracing::warn!("listing playlists failed: {}", e);
+                    None
+                }
+            },
+            false => None,
+        };
+
+        let data = SpotifyThing::User(user, playlists);
+        self.return_data(vec![data], vec![]).await;
+    }
+
+    /// uris of the user's public playlists, a page at a time
+    async fn user_playlists(&self, id: &UserId<'static>) -> ClientResult<Vec<String>> {
+        let pager = self.pager();
+        let mut uris = Vec::new();
+        loop {
+            let offset = uris.len() as u32;
+            let page = pager
+                .with_rate_limit(&self.ratelimiter, || {
+                    self.client
+                        .user_playlists_manual(id.clone(), Some(50), Some(offset))
+                })
+                .await?;
+            uris.extend(page.items.iter().map(|p| p.id.uri()));
+            if page.next.is_none() || page.items.is_empty() {
+                return Ok(uris);
+            }
+        }
+    }
+
+    #[throws(eyre::Report)]
+    #[tracing::instrument(skip(self), err)]
+    async fn create_playlist(mut self, name: String, description: Option<String>) -> String {
+        let client = self.client.user()?.clone();
+        self.acquire().await;
+        let user = self
+            .ratelimiter
+            .with_rate_limit(|| client.current_user(), true)
+            .await?;
+
+        let pl = self
+            .ratelimiter
+            .with_rate_limit(
+                || {
+                    client.user_playlist_create(
+                        user.id.clone(),
+                        &name,
+                        None,
+                        None,
+                        description.as_deref(),
+                    )
+                },
+                true,
+            )
+            .await?;
+
+        tracing::info!(id = %pl.id, "created playlist");
+        pl.id.to_string()
+    }
+
+    #[throws(eyre::Report)]
+    #[tracing::instrument(skip_all, err)]
+    async fn save_to_library(mut self, db: MyDb, ids: Vec<String>, follow: bool) {
+        const MAX_SAVE: usize = 50;
+
+        let client = self.client.user()?.clone();
+        let ids = db::unsaved(&db, ids).await?;
+        let mut tracks = Vec::new();
+        let mut albums = Vec::new();
+        let mut artists = Vec::new();
+        for id in ids.iter() {
+            match parse_uri(id)?.0 {
+                Type::Track => tracks.push(TrackId::from_uri(id)?.clone_static()),
+                Type::Album => albums.push(AlbumId::from_uri(id)?.clone_static()),
+                Type::Artist if follow => artists.push(ArtistId::from_uri(id)?.clone_static()),
+                _ => {}
+            }
+        }
+
+        if follow {
+            // who they are by, fetched if need be. before taking a connection, fetching needs them
+            let uris = tracks.iter().map(Id::uri).collect_vec();
+            for t in get_tracks(&self.actor_ref, &uris)
+                .await?
+                .into_iter()
+                .flatten()
+            {
+                artists.extend(t.artists.into_iter().filter_map(|a| a.id));
+            }
+            let uris = albums.iter().map(Id::uri).collect_vec();
+            for a in get_albums(&self.actor_ref, &uris)
+                .await?
+                .into_iter()
+                .flatten()
+            {
+                artists.extend(a.artists.into_iter().filter_map(|a| a.id));
+            }
+        }
+        let artists = artists.into_iter().unique().collect_vec();
+
+        self.acquire().await;
+        for chunk in tracks.chunks(MAX_SAVE) {
+            self.ratelimiter
+                .with_rate_limit(
+                    || client.current_user_saved_tracks_add(chunk.to_vec()),
+                    true,
+                )
+                .await?;
+        }
+        for chunk in albums.chunks(MAX_SAVE) {
+            self.ratelimiter
+                .with_rate_limit(
+                    || client.current_user_saved_albums_add(chunk.to_vec()),
+                    true,
+                )
+                .await?;
+        }
+        for chunk in artists.chunks(MAX_SAVE) {
+            self.ratelimiter
+                .with_rate_limit(|| client.user_follow_artists(chunk.to_vec()), true)
+                .await?;
+        }
+        self.c = None; // drop lease
+
+        tracing::info!(
+            tracks = tracks.len(),
+            albums = albums.len(),
+            artists = artists.len(),
+            "saved to library"
+        );
+        let saved = ids.into_iter().chain(artists.iter().map(Id::uri));
+        db::mark_saved(&db, saved.collect()).await?;
+    }
+
+    /// fetch the whole playlist, for syncing
+    #[throws(eyre::Report)]
+    async fn full_playlist(&mut self, id: PlaylistId<'static>) -> FullPlaylist {
+        self.acquire().await;
+        let pl = self
+            .ratelimiter
+            .with_rate_limit(|| self.client.playlist(id.clone(), None, self.market), true)
+            .await;
+        self.c = None; // drop lease
+
+        let mut pl = pl?;
+        depageinate_playlist_fast(
+            &self.client,
+            &self.ratelimiter,
+            &self.pager(),
+            self.market,
+            &mut pl,
+            async |a| {
+                tracing::info!("page {} / {}", a.offset, a.total);
+            },
+        )
+        .await?;
+        pl
+    }
+
+    #[tracing::instrument(skip_all, fields(id = %id))]
     async fn playlist_sync(
-        self,
+        mut self,
         id: PlaylistId<'static>,
-        snapshot: Option<String>,
-        tracks: Vec<PlayableItem>,
+        tracks: Vec<TrackId<'static>>,
+        no_delete: bool,
+        retries: usize,
     ) {
+        let ret = self.sync_verified(id, tracks, no_delete, retries).await;
+        let Ok(outcome) = ret.log::<OnError>() else {
+            return;
+        };
+        match outcome {
+            SyncOutcome::Failed { attempts } => {
+                tracing::error!(attempts, "playlist still differs from target after sync")
+            }
+            SyncOutcome::Replaced { attempts } => {
+                tracing::warn!(attempts, "playlist synced by full replace")
+            }
+            outcome => tracing::info!(?outcome, "playlist synced"),
+        }
+    }
+
+    /// Apply the plan, then re-fetch and compare against the target.
+    /// Whatever still differs gets a fresh plan, and once the `retries` run out a full replace
+    /// (except for no_delete playlists, where that would throw away hand-added tracks).
+    #[throws(eyre::Report)]
+    async fn sync_verified(
+        &mut self,
+        id: PlaylistId<'static>,
+        tracks: Vec<TrackId<'static>>,
+        no_delete: bool,
+        retries: usize,
+    ) -> SyncOutcome {
+        if tracks.is_empty() {
+            throw!(eyre::eyre!("refusing to sync an empty playlist"));
+        }
+        let writer = self.client.user()?.clone();
+
+        let mut pl = self.full_playlist(id.clone()).await?;
+        let mut outcome = SyncOutcome::Unchanged;
+        for attempt in 1.. {
+            let actions = plan_sync(&pl, tracks.clone(), no_delete);
+            if actions.is_empty() {
+                break;
+            }
+
+            let actions = match attempt {
+                1 => actions,
+                a if a <= retries + 1 => {
+                    tracing::warn!(attempt, n = actions.len(), "playlist differs, retrying");
+                    actions
+                }
+                a if a == retries + 2 && !no_delete => {
+                    tracing::warn!(attempt, "playlist differs, replacing it");
+                    outcome = SyncOutcome::Replaced { attempts: a };
+                    let items = tracks.iter().cloned().map(PlayableId::Track).collect_vec();
+                    full_replace(items, Default::default())
+                }
+                a => return SyncOutcome::Failed { attempts: a - 1 },
+            };
+            if !matches!(outcome, SyncOutcome::Replaced { .. }) {
+                outcome = SyncOutcome::Synced { attempts: attempt };
+            }
+
+            // a failed request leaves the playlist somewhere in between, which the re-fetch will show
+            playlist_apply(&writer, &self.ratelimiter, &self.pager(), &pl, actions)
+                .await
+                .log_and_drop::<OnError>();
+            pl = self.full_playlist(id.clone()).await?;
+        }
+
+        // an unchanged playlist keeps its old stamp, that is when it last changed
+        if outcome != SyncOutcome::Unchanged {
+            stamp_description(&writer, &self.ratelimiter, &self.pager(), &pl).await?;
+        }
+        outcome
     }
 
+    #[throws(eyre::Report)]
+    #[tracing::instrument(skip_all, fields(id = %id))]
+    async fn playlist_plan(
+        mut self,
+        id: PlaylistId<'static>,
+        tracks: Vec<TrackId<'static>>,
+        no_delete: bool,
+    ) -> Vec<Actions<PlayableId<'static>>> {
+        let pl = self.full_playlist(id).await?;
+        plan_sync(&pl, tracks, no_delete)
+    }
+
+    /// wait for the module to give us a connection, unless we already have one
     async fn acquire(&mut self) {
         if self.c.is_none() {
-            let c = self
-                .ratelimiter
-                .connections
-                .clone()
-                .acquire_owned()
-                .await
-                .unwrap();
-            self.c = Some(c);
+            self.c = Some(self.pager().lease().await);
         }
-        assert!(self.c.is_some());
     }
 
-    async fn return_data(self, data: Vec<SpotifyThing>) {
+    fn pager(&self) -> Pager {
+        Pager {
+            actor_ref: self.actor_ref.clone(),
+            priority: self.priority,
+        }
+    }
+
+    /// A batch failed with something other than a rate limit, most likely one bad id.
+    /// Fetch them one at a time instead, so only the bad ones get recorded as failed.
+    async fn one_by_one<'a, I: Id, T>(
+        &'a self,
+        ids: &'a [I],
+        f: impl Fn(&'a I) -> BoxFuture<'a, ClientResult<T>>,
+    ) -> (Vec<T>, Vec<(String, String)>) {
+        let mut found = Vec::new();
+        let mut failed = Vec::new();
+        for id in ids {
+            match self.ratelimiter.with_rate_limit(|| f(id), true).await {
+                Ok(t) => found.push(t),
+                Err(e) => failed.push((id.uri(), e.to_string())),
+            }
+        }
+        (found, failed)
+    }
+
+    /// `failed` is (uri, reason) for ids which spotify wouldn't give us
+    async fn return_data(self, data: Vec<SpotifyThing>, failed: Vec<(String, String)>) {
         self.actor_ref
             .tell(FetcherData {
                 data,
+                failed,
                 reqid: Some(self.reqid),
             })
             .send()
@@ -433,35 +1624,143 @@ impl Conn {
             .unwrap();
     }
 
-    fn new(&self, c: Option<OwnedSemaphorePermit>) -> Conn {
+    fn new(&self, c: Option<Lease>) -> Conn {
         Self {
             actor_ref: self.actor_ref.clone(),
             client: self.client.clone(),
+            market: self.market,
             ratelimiter: self.ratelimiter.clone(),
             reqid: unique_id(),
+            priority: self.priority,
             c,
         }
     }
 }
 
+/// A connection handed out by [`Module::task`].
+/// Dropping it runs the task again, so whatever is waiting gets the connection.
+struct Lease {
+    c: Option<OwnedSemaphorePermit>,
+    trigger: Option<TriggerTask<Task, Module>>,
+}
+
+impl Lease {
+    fn new(c: OwnedSemaphorePermit, trigger: TriggerTask<Task, Module>) -> Self {
+        Lease {
+            c: Some(c),
+            trigger: Some(trigger),
+        }
+    }
+
+    /// give the connection back without running the task
+    fn forget_trigger(mut self) {
+        self.trigger = None;
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        // release before triggering, otherwise the task could find no free connection
+        self.c = None;
+        if let Some(trigger) = self.trigger.take() {
+            // on shutdown there is nobody left to tell
+            if trigger.actor_ref.is_alive() {
+                trigger.trigger_task();
+            }
+        }
+    }
+}
+
+/// Gets connections for the pages of a request which is already underway.
+/// They go through [`Module::priotity`] like everything else, at the priority of the request.
+#[derive(Clone)]
+pub struct Pager {
+    actor_ref: ActorRef<Module>,
+    priority: Priority,
+}
+
+impl Pager {
+    async fn lease(&self) -> Lease {
+        let (tx, rx) = oneshot::channel();
+        self.actor_ref
+            .tell(WaitLease {
+                priority: self.priority,
+                tx,
+            })
+            .send()
+            .await
+            .unwrap();
+        rx.await.expect("spotify module went away")
+    }
+
+    /// [`RateLimiter::with_rate_limit`] on a leased connection, which is given back afterwards
+    pub async fn with_rate_limit<'async_trait, F, T>(
+        &self,
+        ratelimiter: &RateLimiter,
+        f: F,
+    ) -> rspotify::ClientResult<T>
+    where
+        F: Fn() -> ::core::pin::Pin<
+            Box<
+                dyn ::core::future::Future<Output = rspotify::ClientResult<T>>
+                    + ::core::marker::Send
+                    + 'async_trait,
+            >,
+        >,
+    {
+        let _c = self.lease().await;
+        ratelimiter.with_rate_limit(f, true).await
+    }
+}
+
 /// Data from the fetcher
+#[derive(Clone)]
 enum SpotifyThing {
     Album(FullAlbum),
     Track(FullTrack),
+    Artist(FullArtist, ArtistExpansion),
     Playlist(FullPlaylist),
+    /// with the uris of their public playlists, if those were asked for
+    User(PublicUser, Option<Vec<String>>),
+}
+
+impl SpotifyThing {
+    fn uri(&self) -> Option<String> {
+        match self {
+            SpotifyThing::Album(a) => Some(a.id.uri()),
+            // asked for by the shared id, what came back might be relinked
+            SpotifyThing::Track(t) => match &t.linked_from {
+                Some(link) => Some(link.id.as_ref()?.uri()),
+                None => Some(t.id.as_ref()?.uri()),
+            },
+            SpotifyThing::Artist(a, _) => Some(a.id.uri()),
+            SpotifyThing::Playlist(p) => Some(p.id.uri()),
+            SpotifyThing::User(u, _) => Some(u.id.uri()),
+        }
+    }
+}
+
+/// What was fetched for an artist, depending on [`ArtistExpand`].
+/// None is left alone in the db, so switching policy doesn't lose anything.
+#[derive(Debug, Clone, Default)]
+struct ArtistExpansion {
+    top_tracks: Option<Vec<String>>,
+    latest_release: Option<String>,
 }
 
 #[derive(Debug, Clone)]
 struct Req<T> {
     pub req: Option<u64>,
     pub id: T,
+    pub priority: Priority,
 }
 
 impl<T> Req<T> {
-    fn new(value: T) -> Self {
+    fn new(value: T, priority: Priority) -> Self {
         Req {
             req: None,
             id: value,
+            priority,
         }
     }
 }
@@ -477,14 +1776,23 @@ impl<T: Clone + PartialEq + Debug + Hash + Eq> Queue<T> {
         self.data.read().iter().filter_map(|f| f.req).count()
     }
 
-    fn add_unique(&self, v: impl IntoIterator<Item = T>) {
+    /// ids which are already queued are bumped up to `priority` if they haven't gone out yet
+    fn add_unique(&self, v: impl IntoIterator<Item = T>, priority: Priority) {
         let mut guard = self.data.write();
-        let mut keys: HashSet<T> = guard.iter().map(|v| v.id.clone()).collect();
+        let wanted = v.into_iter().collect_vec();
+        let bump: HashSet<&T> = wanted.iter().collect();
 
-        let v = v
+        for r in guard.iter_mut() {
+            if r.req.is_none() && r.priority < priority && bump.contains(&r.id) {
+                r.priority = priority;
+            }
+        }
+
+        let mut keys: HashSet<T> = guard.iter().map(|v| v.id.clone()).collect();
+        let v = wanted
             .into_iter()
             .filter(|id| keys.insert(id.clone())) // clever way to ensure unique
-            .map(|id| Req::new(id))
+            .map(|id| Req::new(id, priority))
             .collect_vec();
 
         if !v.is_empty() {
@@ -505,9 +1813,11 @@ impl<T: Clone + PartialEq + Debug + Hash + Eq> Queue<T> {
         max: usize,
         guard: &mut VecDeque<Req<T>>,
     ) -> Option<Vec<T>> {
+        // highest priority first, otherwise in the order they were added
         let free = guard
             .iter_mut()
             .filter(|r| r.req.is_none())
+            .sorted_by_key(|r| std::cmp::Reverse(r.priority))
             .take(max)
             .collect_vec();
 
@@ -536,9 +1846,13 @@ impl<T: Clone + PartialEq + Debug + Hash + Eq> Queue<T> {
         }
     }
 
-    fn ready(&self) -> usize {
+    /// how many haven't gone out yet, counting those at `priority` or above
+    fn ready(&self, priority: Priority) -> usize {
         let guard = self.data.read();
-        guard.iter().filter(|r| r.req.is_none()).count()
+        guard
+            .iter()
+            .filter(|r| r.req.is_none() && r.priority >= priority)
+            .count()
     }
 
     fn remove(&self, reqid: u64) -> Vec<Req<T>> {
@@ -570,6 +1884,23 @@ impl<T: Clone + PartialEq + Debug + Hash + Eq> Queue<T> {
         }
         changed
     }
+
+    /// like [`Queue::release`], but only for `ids`
+    fn release_some(&self, reqid: u64, ids: &[T]) -> usize {
+        let mut guard = self.data.write();
+        let mut changed = 0;
+        for r in guard.iter_mut() {
+            if r.req == Some(reqid) && ids.contains(&r.id) {
+                r.req = None;
+                changed += 1;
+            }
+        }
+
+        if changed > 0 {
+            self.condvar.notify_all();
+        }
+        changed
+    }
 }
 
 static COUNTER: AtomicU64 = AtomicU64::new(0);
@@ -579,41 +1910,90 @@ pub fn unique_id() -> u64 {
 
 #[derive(Clone)]
 pub struct RateLimiter {
+    config: RateLimitConfig,
     sleep_until: Arc<Mutex<Option<Instant>>>,
     pub connections: Arc<tokio::sync::Semaphore>,
     pub revoked_count: Arc<AtomicUsize>,
 }
 
-impl Default for RateLimiter {
-    fn default() -> Self {
+/// See [`Module::rate_limit_state`]
+#[derive(Debug, Clone, kameo::Reply)]
+pub struct RateLimiterState {
+    /// how long until requests go out again, if spotify told us to back off
+    pub suspended_for: Option<Duration>,
+    /// connections taken away until the rate limit is over
+    pub revoked: usize,
+    /// requests talking to spotify right now
+    pub in_flight: usize,
+    /// requests underway which are waiting on a connection for their next page
+    pub waiting_pages: usize,
+    /// ids in the queues which haven't gone out yet
+    pub queued: usize,
+}
+
+impl std::fmt::Display for RateLimiterState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.suspended_for {
+            Some(d) => write!(f, "rate limited for another {}s", d.as_secs())?,
+            None => write!(f, "not rate limited")?,
+        }
+        write!(
+            f,
+            ", {} in flight, {} connections revoked, {} pages waiting, {} queued",
+            self.in_flight, self.revoked, self.waiting_pages, self.queued
+        )
+    }
+}
+
+/// Retry-After gets clamped to this, spotify has been known to send nonsense
+const LONGEST_SUSPEND: f32 = 24.0 * 60.0 * 60.0;
+
+impl RateLimiter {
+    pub fn new(config: RateLimitConfig) -> Self {
         Self {
-            connections: Semaphore::new(4).into(),
+            connections: Semaphore::new(config.connections).into(),
             sleep_until: Default::default(),
             revoked_count: Default::default(),
+            config,
         }
     }
-}
 
-impl RateLimiter {
-    pub async fn with_rate_limit_acquired<'async_trait, F, T>(
-        &self,
-        f: F,
-    ) -> rspotify::ClientResult<T>
-    where
-        F: Fn() -> ::core::pin::Pin<
-            Box<
-                dyn ::core::future::Future<Output = rspotify::ClientResult<T>>
-                    + ::core::marker::Send
-                    + 'async_trait,
-            >,
-        >,
-    {
-        // let _c = match needs_aquired {
-        //     true => Some(),
-        //     false => None,
-        // };
-        let _c = self.connections.acquire().await;
-        self.with_rate_limit(f, true).await
+    /// when requests may go out again, if that's still in the future
+    pub fn suspended_until(&self) -> Option<Instant> {
+        self.sleep_until.lock().filter(|t| *t > Instant::now())
+    }
+
+    /// once the rate limit has run out, give back the connections it took
+    pub fn resume_if_due(&self) {
+        let mut guard = self.sleep_until.lock();
+        if guard.is_some_and(|t| t > Instant::now()) {
+            return;
+        }
+        *guard = None;
+        self.connections.add_permits(
+            self.revoked_count
+                .swap(0, std::sync::atomic::Ordering::Relaxed),
+        );
+    }
+
+    pub fn state(&self, queued: usize, waiting_pages: usize) -> RateLimiterState {
+        let revoked = self
+            .revoked_count
+            .load(std::sync::atomic::Ordering::Relaxed);
+        let in_flight = self
+            .config
+            .connections
+            .saturating_sub(revoked)
+            .saturating_sub(self.connections.available_permits());
+        RateLimiterState {
+            suspended_for: self
+                .suspended_until()
+                .map(|t| t.duration_since(Instant::now())),
+            revoked,
+            in_flight,
+            waiting_pages,
+            queued,
+        }
     }
 
     pub async fn with_rate_limit<'async_trait, F, T>(
@@ -631,7 +2011,6 @@ impl RateLimiter {
         >,
     {
         let mut count = 0;
-        const MAX_TRIES: i32 = 8;
 
         loop {
             loop {
@@ -674,15 +2053,17 @@ impl RateLimiter {
             // RATE LIMIT HIT
             {
                 // first gobble up all the connections
-                let restore_permits = self.connections.forget_permits(100);
-                let c = self
-                    .revoked_count
+                let restore_permits = self.connections.forget_permits(self.config.connections);
+                self.revoked_count
                     .fetch_add(restore_permits, std::sync::atomic::Ordering::Relaxed);
-                assert!(c + restore_permits < 10);
             }
 
             let n = rl.retry_after.unwrap_or(5.0);
             let n = n * 1.1f32.powi(count); // wait longer than spotify tells us too.
+            let n = match n.is_finite() {
+                true => n.clamp(0.0, LONGEST_SUSPEND),
+                false => LONGEST_SUSPEND,
+            };
 
             let mut t = Instant::now() + Duration::from_secs_f32(n);
 
@@ -695,12 +2076,13 @@ impl RateLimiter {
             tracing::warn!(RetryAfter = n, "RATE LIMIT [{}]", count);
             count += 1;
 
-            if n > 30.0 {
-                // TODO handle better, I got maxint once
-                panic!("BAD RATE LIMIT");
+            if n > self.config.max_wait_secs {
+                // the module holds the queues until then, see Module::task
+                tracing::warn!(RetryAfter = n, "RATE LIMIT suspending fetching");
+                return v;
             }
 
-            if count >= MAX_TRIES || !retry {
+            if count as u32 >= self.config.max_tries || !retry {
                 tracing::error!("RATE LIMIT [{}] abort", count);
                 return v;
             }
@@ -111,6 +111,16 @@ pub mod chat {
 
         pub channel_id: String,
     }
+
+    /// a `!command` from chat, the leading `!` is stripped
+    #[derive(Debug, Clone)]
+    pub struct ChatCommand {
+        pub service: Service,
+        pub channel_id: String,
+        pub text: String,
+    }
+
+    impl crate::utils::pubsub::Topic for ChatCommand {}
 }
 
 pub mod music {
@@ -151,7 +161,7 @@ impl Link {
             Some(Kind::Album) => "album",
             Some(Kind::Playlist) => "playlist",
             Some(Kind::Artist) => "artist",
-            Some(Kind::User) => "playlist",
+            Some(Kind::User) => "user",
             None => "unknown",
         };
         RecordId::from_table_key(table, &self.id)
@@ -162,7 +172,9 @@ fn urlfmt(url: &Url, f: &mut std::fmt::Formatter) -> Result<(), std::fmt::Error>
     write!(f, "Url(\"{}\")", url.as_str())
 }
 
-#[derive(Debug, Copy, Clone, EnumString, Display, DeserializeFromStr, SerializeDisplay)]
+#[derive(
+    Debug, Copy, Clone, PartialEq, Eq, EnumString, Display, DeserializeFromStr, SerializeDisplay,
+)]
 #[strum(ascii_case_insensitive)]
 #[strum(serialize_all = "lowercase")]
 pub enum Kind {
@@ -114,8 +114,10 @@ func (self *ServicePlaylist) Update(p *Playlist) error {
 }
  */
 
+use std::collections::{HashMap, HashSet, VecDeque};
+
 use itertools::Itertools;
-use similar::{algorithms::IdentifyDistinct, capture_diff};
+use similar::{algorithms::IdentifyDistinct, capture_diff, capture_diff_slices, Algorithm, DiffOp};
 
 #[derive(Debug, Clone, Copy)]
 /// Configures how playlist updates are sequenced
@@ -145,97 +147,166 @@ pub fn sequence<T: Eq + core::hash::Hash + Clone>(
     after: Vec<T>,
     opt: SequenceOptions,
 ) -> Vec<Actions<T>> {
-    // list of api requests ie. adds and deletes (return value)
+    // list of api requests ie. adds, deletes and moves (return value)
     let mut actions = Vec::new();
 
     // Diffs are hard. We know the index of things in the new struct, but not in the steps inbetween.
-    // Deletes first, then recompute diff, then Adds.
+    // Deletes first, then Moves, then Adds. Each pass updates a simulation of the playlist.
     // Keep in mind we do not have the ability specify snapshot for Adds
-    let mut simulated = before.clone();
 
-    // First pass: Deletions
+    // Match up items in before and after, matched items are kept (possibly moved), the rest are deleted or added.
+    // Items the diff says are equal stay in place, leftover copies of an id are paired up to become moves.
+    // NOTE: similar's Lcs algorithm gives suboptimal diffs when there is a common prefix, so use Myers
+    let mut partner: Vec<Option<usize>> = vec![None; after.len()]; // after index -> before index
+    let mut matched = vec![false; before.len()];
     let h = IdentifyDistinct::<u32>::new(&before, 0..before.len(), &after, 0..after.len());
     let diff = capture_diff(
-        similar::Algorithm::Lcs,
+        Algorithm::Myers,
         h.old_lookup(),
         h.old_range(),
         h.new_lookup(),
         h.new_range(),
     );
-
-    for d in diff.iter().rev() {
-        match d {
-            similar::DiffOp::Replace {
-                old_index, old_len, ..
+    for d in diff {
+        if let DiffOp::Equal {
+            old_index,
+            new_index,
+            len,
+        } = d
+        {
+            for k in 0..len {
+                partner[new_index + k] = Some(old_index + k);
+                matched[old_index + k] = true;
             }
-            | similar::DiffOp::Delete {
-                old_index, old_len, ..
-            } => {
-                let index = *old_index..(old_index + old_len);
-                let a = before[index.clone()]
-                    .iter()
-                    .enumerate()
-                    .chunks(opt.max_n)
-                    .into_iter()
-                    .map(|chunk| match opt.delete_positional {
-                        true => {
-                            // delete by id and index
-                            let chunk = chunk.map(|(i, t)| (i, t.clone())).collect_vec();
-                            Actions::Delete(chunk)
-                        }
-                        false => {
-                            // delete by id (all occurances)
-                            let chunk = chunk.map(|(_, t)| t.clone()).collect_vec();
-                            Actions::DeleteAll(chunk)
-                        }
-                    })
-                    .collect_vec();
-                actions.extend(a);
-
-                // update simulation
-                if opt.delete_positional {
-                    let removed = simulated.drain(index.clone()).collect_vec();
-                    assert!(removed == before[index], "diffs need to be sorted");
-                } else {
-                    // remove *all occurances* of ids
-                    simulated.retain(|t| !before[index.clone()].contains(t))
-                }
+        }
+    }
+
+    let mut leftover = HashMap::<&T, VecDeque<usize>>::new();
+    for (i, t) in before.iter().enumerate().filter(|(i, _)| !matched[*i]) {
+        leftover.entry(t).or_default().push_back(i);
+    }
+    for (j, t) in after.iter().enumerate() {
+        if partner[j].is_none() {
+            if let Some(i) = leftover.get_mut(t).and_then(|q| q.pop_front()) {
+                partner[j] = Some(i);
+                matched[i] = true;
             }
-            _ => { /* skip inserts on first pass */ }
         }
     }
 
-    // Second pass: Insertions
-    let h = IdentifyDistinct::<u32>::new(&simulated, 0..simulated.len(), &after, 0..after.len());
-    let diff = capture_diff(
-        similar::Algorithm::Lcs,
-        h.old_lookup(),
-        h.old_range(),
-        h.new_lookup(),
-        h.new_range(),
-    );
-    for d in diff.iter().rev() {
-        match d {
-            similar::DiffOp::Insert {
-                old_index,
-                new_index,
-                new_len,
-                ..
-            } => {
-                let new_slice = *new_index..(new_index + new_len);
-                let elements = after[new_slice.clone()].to_owned();
-                actions.push(Actions::Add(elements.clone(), *old_index));
-
-                // update simulation
-                simulated.splice(old_index..old_index, elements);
-
-                let old_slice = *old_index..(old_index + new_len);
-                assert!(simulated[old_slice] == after[new_slice]);
+    // the simulation holds indices into before, so that duplicate ids can be told apart
+    let mut simulated = (0..before.len()).collect_vec();
+
+    // First pass: Deletions
+    let deleted = (0..before.len()).filter(|i| !matched[*i]).collect_vec();
+    if opt.delete_positional {
+        // delete from the back, so positions of the remaining deletions do not shift
+        for chunk in deleted.iter().rev().chunks(opt.max_n).into_iter() {
+            let chunk = chunk.copied().collect_vec();
+            let a = chunk.iter().map(|&i| {
+                let position = simulated.iter().position(|s| *s == i).unwrap();
+                (position, before[i].clone())
+            });
+            actions.push(Actions::Delete(a.collect_vec()));
+
+            // update simulation
+            simulated.retain(|i| !chunk.contains(i));
+        }
+    } else {
+        // delete by id, this removes *all occurances*, so copies we wanted to keep have to be re-added
+        let ids = deleted.iter().map(|&i| &before[i]).unique().collect_vec();
+        for chunk in ids.chunks(opt.max_n) {
+            actions.push(Actions::DeleteAll(
+                chunk.iter().cloned().cloned().collect_vec(),
+            ));
+        }
+
+        // update simulation
+        let ids: HashSet<&T> = ids.into_iter().collect();
+        simulated.retain(|&i| !ids.contains(&before[i]));
+        for p in partner.iter_mut() {
+            if p.is_some_and(|i| ids.contains(&before[i])) {
+                *p = None;
             }
-            similar::DiffOp::Equal { .. } => {}
-            _ => {
-                unreachable!("should not have deletions on second pass")
+        }
+    }
+
+    // Second pass: Moves
+    // simulated is now a permutation of target, items on their longest common subsequence can stay put.
+    // Every other item is moved to just after the item which precedes it in target, working front to back.
+    let target = partner.iter().filter_map(|p| *p).collect_vec();
+    let staying: HashSet<usize> = capture_diff_slices(Algorithm::Myers, &simulated, &target)
+        .into_iter()
+        .filter_map(|d| match d {
+            DiffOp::Equal { old_index, len, .. } => {
+                Some(simulated[old_index..old_index + len].to_vec())
             }
+            _ => None,
+        })
+        .flatten()
+        .collect();
+
+    let mut j = 0;
+    while j < target.len() {
+        if staying.contains(&target[j]) {
+            j += 1;
+            continue;
+        }
+
+        let range_start = simulated.iter().position(|s| *s == target[j]).unwrap();
+        let insert_before = match j {
+            0 => 0,
+            _ => simulated.iter().position(|s| *s == target[j - 1]).unwrap() + 1,
+        };
+
+        // grab the following items too, if they need moving and are already in the right order
+        let mut range_length = 1;
+        while range_length < opt.max_n
+            && j + range_length < target.len()
+            && !staying.contains(&target[j + range_length])
+            && simulated.get(range_start + range_length) == Some(&target[j + range_length])
+        {
+            range_length += 1;
+        }
+
+        if insert_before != range_start {
+            actions.push(Actions::Move {
+                range_start,
+                range_length,
+                insert_before,
+            });
+
+            // update simulation
+            let moved = simulated
+                .drain(range_start..range_start + range_length)
+                .collect_vec();
+            let at = match insert_before > range_start {
+                true => insert_before - range_length,
+                false => insert_before,
+            };
+            simulated.splice(at..at, moved);
+        }
+
+        j += range_length;
+    }
+    assert!(simulated == target);
+
+    // Third pass: Insertions
+    // everything which is kept is now in order, so working front to back the index in after is the index to insert at
+    let mut simulated = simulated
+        .into_iter()
+        .map(|i| before[i].clone())
+        .collect_vec();
+    let missing = (0..after.len()).filter(|j| partner[*j].is_none());
+    for (_, run) in &missing.enumerate().chunk_by(|(n, j)| j - n) {
+        let run = run.map(|(_, j)| j).collect_vec();
+        for chunk in run.chunks(opt.max_n) {
+            let index = chunk[0];
+            let elements = chunk.iter().map(|&j| after[j].clone()).collect_vec();
+            actions.push(Actions::Add(elements.clone(), index));
+
+            // update simulation
+            simulated.splice(index..index, elements);
         }
     }
 
@@ -243,8 +314,8 @@ pub fn sequence<T: Eq + core::hash::Hash + Clone>(
 
     // Check if current plan is worse (more api requests) than clearing and rebuilding the whole playlist.
     // Note: if actually using snapshot actions, then correct actions count.
-    let worst_case = after.len() / opt.max_n;
-    if actions.len() >= worst_case {
+    let worst_case = after.len().div_ceil(opt.max_n).max(1);
+    if actions.len() > worst_case {
         full_replace(after, opt)
     } else {
         actions
@@ -273,12 +344,66 @@ pub fn full_replace<T: Clone + Eq>(after: Vec<T>, opt: SequenceOptions) -> Vec<A
     actions
 }
 
+/// Keep entries we can't add or remove (local files, unavailable tracks) where they are.
+/// Each fixed entry is put into `after` right behind the entry it currently follows
+/// (skipping ones that are going away), so a plan from `before` never has to touch it.
+/// Fixed entries must be distinct, eg. tagged with their position.
+pub fn anchor_fixed<T: Eq + core::hash::Hash + Clone>(
+    before: &[T],
+    after: Vec<T>,
+    is_fixed: impl Fn(&T) -> bool,
+) -> Vec<T> {
+    let kept: HashSet<&T> = after.iter().collect();
+
+    // fixed entries, by the kept entry before them (None for the start)
+    let mut behind = HashMap::<Option<&T>, Vec<T>>::new();
+    let mut prev = None;
+    for t in before {
+        if is_fixed(t) {
+            behind.entry(prev).or_default().push(t.clone());
+        } else if kept.contains(t) {
+            prev = Some(t);
+        }
+    }
+
+    let mut out = behind.remove(&None).unwrap_or_default();
+    for t in after.iter() {
+        out.push(t.clone());
+        if let Some(v) = behind.remove(&Some(t)) {
+            out.extend(v);
+        }
+    }
+    out
+}
+
+/// Plan for append-only playlists (`NoDelete` in the go version).
+/// New tracks are prepended and nothing is ever removed, so tracks added by hand survive.
+/// Like the go version, tracks already in the playlist (or repeated in `after`) are skipped.
+pub fn prepend_only<T: Eq + core::hash::Hash + Clone>(
+    before: &[T],
+    after: Vec<T>,
+    opt: SequenceOptions,
+) -> Vec<Actions<T>> {
+    let mut seen: HashSet<&T> = before.iter().collect();
+    let new = after
+        .iter()
+        .filter(|t| seen.insert(t))
+        .cloned()
+        .collect_vec();
+
+    new.chunks(opt.max_n)
+        .enumerate()
+        .map(|(i, chunk)| Actions::Add(chunk.to_vec(), i * opt.max_n))
+        .collect_vec()
+}
+
 pub type SnapshotKey = usize;
 
 // Available operations:
-/// add up to 100 ids (position for group NO SNAPSHOT)
-/// move up to 100 contiguous ids
+// add up to 100 ids (position for group NO SNAPSHOT)
+// move up to 100 contiguous ids
 
+#[derive(Debug, Clone)]
 pub enum Actions<T> {
     /// add up to 100 ids to end of playlist
     Append(Vec<T>),
@@ -294,28 +419,220 @@ pub enum Actions<T> {
 
     /// replace (clear) the whole playlist with up to 100 ids
     Replace(Vec<T>),
+
+    /// move a contiguous range of up to 100 items, keeps their added date
+    /// `insert_before` is the index in the playlist before the move, like spotify's reorder endpoint
+    Move {
+        range_start: usize,
+        range_length: usize,
+        insert_before: usize,
+    },
+}
+
+impl<T> Actions<T> {
+    /// convert the ids, dropping any which can't be converted.
+    /// positions are kept as they are, so only drop ids which were never meant to be sent.
+    pub fn filter_map<U>(self, mut f: impl FnMut(T) -> Option<U>) -> Actions<U> {
+        let mut vec = |v: Vec<T>| v.into_iter().filter_map(&mut f).collect_vec();
+        match self {
+            Actions::Append(v) => Actions::Append(vec(v)),
+            Actions::Add(v, i) => Actions::Add(vec(v), i),
+            Actions::DeleteAll(v) => Actions::DeleteAll(vec(v)),
+            Actions::Replace(v) => Actions::Replace(vec(v)),
+            Actions::Delete(v) => Actions::Delete(
+                v.into_iter()
+                    .filter_map(|(i, t)| Some((i, f(t)?)))
+                    .collect(),
+            ),
+            Actions::Move {
+                range_start,
+                range_length,
+                insert_before,
+            } => Actions::Move {
+                range_start,
+                range_length,
+                insert_before,
+            },
+        }
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::collections::HashSet;
+
     use itertools::Itertools;
 
     use crate::utils::diff::SequenceOptions;
 
-    use super::sequence;
+    use super::{anchor_fixed, prepend_only, sequence, Actions, SnapshotKey};
+
+    /// fake playlist which handles requests the way spotify does.
+    /// every request makes a new snapshot; positions in snapshot-relative requests (delete, move)
+    /// refer to the snapshot given, and are then applied to the current items.
+    struct Spotify<T> {
+        /// (uid, id), uid tells apart repeats of the same id
+        items: Vec<(usize, T)>,
+        snapshots: Vec<Vec<(usize, T)>>,
+        next_uid: usize,
+        max_n: usize,
+    }
+
+    impl<T: Clone + PartialEq + std::fmt::Debug> Spotify<T> {
+        fn new(items: Vec<T>, max_n: usize) -> Self {
+            let items = items.into_iter().enumerate().collect_vec();
+            Self {
+                next_uid: items.len(),
+                snapshots: vec![items.clone()],
+                items,
+                max_n,
+            }
+        }
+
+        fn snapshot(&self) -> SnapshotKey {
+            self.snapshots.len() - 1
+        }
+
+        fn ids(&self) -> Vec<T> {
+            self.items.iter().map(|(_, t)| t.clone()).collect_vec()
+        }
+
+        fn tag(&mut self, v: &[T]) -> Vec<(usize, T)> {
+            let tagged = v
+                .iter()
+                .cloned()
+                .enumerate()
+                .map(|(i, t)| (self.next_uid + i, t));
+            let tagged = tagged.collect_vec();
+            self.next_uid += v.len();
+            tagged
+        }
+
+        /// no snapshot means the latest one, returns the new snapshot
+        fn request(&mut self, action: &Actions<T>, snapshot: Option<SnapshotKey>) -> SnapshotKey {
+            let base = self.snapshots[snapshot.unwrap_or(self.snapshot())].clone();
+            match action {
+                Actions::Append(v) => {
+                    assert!(v.len() <= self.max_n);
+                    let tagged = self.tag(v);
+                    self.items.extend(tagged);
+                }
+                Actions::Add(v, i) => {
+                    assert!(v.len() <= self.max_n);
+                    assert!(*i <= self.items.len());
+                    let tagged = self.tag(v);
+                    self.items.splice(*i..*i, tagged);
+                }
+                Actions::Delete(v) => {
+                    assert!(v.len() <= self.max_n);
+                    // spotify rejects the request if the ids don't match the snapshot
+                    for (i, t) in v {
+                        assert_eq!(&base[*i].1, t, "wrong id at position {}", i);
+                    }
+                    let uids: HashSet<usize> = v.iter().map(|(i, _)| base[*i].0).collect();
+                    self.items.retain(|(u, _)| !uids.contains(u));
+                }
+                Actions::DeleteAll(v) => {
+                    assert!(v.len() <= self.max_n);
+                    self.items.retain(|(_, t)| !v.contains(t));
+                }
+                Actions::Replace(v) => {
+                    assert!(v.len() <= self.max_n);
+                    self.items = self.tag(v);
+                }
+                Actions::Move {
+                    range_start,
+                    range_length,
+                    insert_before,
+                } => {
+                    assert!(*range_length <= self.max_n);
+                    assert!(*insert_before <= base.len());
+                    assert!(
+                        insert_before <= range_start
+                            || *insert_before >= range_start + range_length,
+                        "inserting inside the moved range"
+                    );
+                    let range = &base[*range_start..range_start + range_length];
+                    let uids: HashSet<usize> = range.iter().map(|(u, _)| *u).collect();
+                    let anchor = base.get(*insert_before).map(|(u, _)| *u);
+
+                    let moved = self.items.iter().filter(|(u, _)| uids.contains(u)).cloned();
+                    let moved = moved.collect_vec();
+                    self.items.retain(|(u, _)| !uids.contains(u));
+                    let at = match anchor {
+                        Some(a) => self.items.iter().position(|(u, _)| *u == a).unwrap(),
+                        None => self.items.len(),
+                    };
+                    self.items.splice(at..at, moved);
+                }
+            }
+            self.snapshots.push(self.items.clone());
+            self.snapshot()
+        }
+
+        /// run requests one after another, passing along the snapshot from each response
+        fn apply(&mut self, actions: &[Actions<T>]) -> Vec<T> {
+            let mut snapshot = self.snapshot();
+            for a in actions {
+                snapshot = self.request(a, Some(snapshot));
+            }
+            self.ids()
+        }
+    }
+
+    /// sequence, and check the result gets from before to after
+    fn check<T: Clone + Eq + core::hash::Hash + std::fmt::Debug>(
+        before: Vec<T>,
+        after: Vec<T>,
+        opts: SequenceOptions,
+    ) -> Vec<Actions<T>> {
+        let actions = sequence(before.clone(), after.clone(), opts);
+        let mut spotify = Spotify::new(before, opts.max_n);
+        assert_eq!(spotify.apply(&actions), after);
+        actions
+    }
 
     #[test]
     /// minimal test of playlist update sequenceing logic
     fn test_delete_append() {
         let before = vec!["red", "blue", "green", "yellow"];
         let after = vec!["blue", "green", "yellow", "black"];
-        sequence(before.clone(), after.clone(), Default::default());
-        sequence(after, before, Default::default());
+        check(before.clone(), after.clone(), Default::default());
+        check(after, before, Default::default());
+    }
+
+    #[test]
+    /// positional deletes spread over several requests, each relative to the previous snapshot
+    fn test_delete_positional() {
+        let before = vec!["a", "b", "a", "c", "a", "b", "a"];
+        let after = vec!["b", "a", "c", "b"];
+        let opts = SequenceOptions {
+            delete_positional: true,
+            max_n: 1,
+        };
+        let actions = check(before, after, opts);
+        assert!(actions.iter().all(|a| matches!(a, Actions::Delete(_))));
+    }
+
+    #[test]
+    /// reorders become moves, not deletes and re-adds
+    fn test_move() {
+        let before = (0..500).collect_vec();
+        let mut after = before.clone();
+        let block = after.drain(100..150).collect_vec();
+        after.extend(block); // move a block to the end
+        after.swap(3, 7); // swap two tracks
+        let last = after.pop().unwrap();
+        after.insert(0, last); // move one track to the front
+
+        let actions = check(before, after, Default::default());
+        assert!(actions.iter().all(|a| matches!(a, Actions::Move { .. })));
+        assert!(actions.len() <= 4, "{:?}", actions);
     }
 
     #[test]
     /// robust test of playlist update sequenceing logic
-    /// simulates many additions, deletions of varying sizes, including duplicate ids
+    /// simulates many additions, deletions and moves of varying sizes, including duplicate ids
     fn test_big_playlist() {
         fn test(opts: SequenceOptions) {
             let mut before = (0..2000).map(|v| v.to_string()).collect_vec();
@@ -333,17 +650,71 @@ mod test {
             after.drain(255..367); // repeat id delete span
             after.drain(203..204); // delete (repeat id, both)
 
-            // sequence has its own asserts and simulation, test passes if it doesnt panic
-            sequence(before.clone(), after.clone(), opts);
+            let block = after.drain(600..720).collect_vec();
+            after.splice(10..10, block); // big move
+            after.swap(800, 900); // swap
+
+            check(before.clone(), after.clone(), opts);
 
             // going backwards also makes a good test
-            sequence(after, before, opts);
+            check(after, before, opts);
         }
 
         test(Default::default());
         test(SequenceOptions {
-            delete_positional: false,
+            delete_positional: true,
             ..Default::default()
         });
     }
+
+    #[test]
+    /// fixed entries (local files) stay behind the same track, and are never added or deleted
+    fn test_anchor_fixed() {
+        let fixed = |t: &&str| t.starts_with("local");
+        let before = vec![
+            "local0", "a", "b", "local1", "local2", "c", "d", "local3", "e",
+        ];
+        let after = vec!["x", "e", "a", "b", "c", "y"];
+
+        let anchored = anchor_fixed(&before, after, fixed);
+        assert_eq!(
+            anchored,
+            vec!["local0", "x", "e", "a", "b", "local1", "local2", "c", "local3", "y"]
+        );
+
+        // small requests, so it doesn't fall back to a full replace
+        let opts = SequenceOptions {
+            delete_positional: true,
+            max_n: 1,
+        };
+        let actions = check(before, anchored, opts);
+        for a in actions {
+            match a {
+                Actions::Append(v) | Actions::Add(v, _) | Actions::DeleteAll(v) => {
+                    assert!(!v.iter().any(fixed))
+                }
+                Actions::Delete(v) => assert!(!v.iter().any(|(_, t)| fixed(t))),
+                Actions::Replace(_) => panic!("should not need a full replace"),
+                Actions::Move { .. } => {}
+            }
+        }
+    }
+
+    #[test]
+    /// no_delete mode prepends new tracks, skipping anything already there
+    fn test_prepend_only() {
+        let before = vec!["red", "blue", "green"];
+        let after = vec!["black", "blue", "white", "black", "pink"];
+        let opts = SequenceOptions {
+            max_n: 2,
+            ..Default::default()
+        };
+
+        let actions = prepend_only(&before, after, opts);
+        assert!(actions.iter().all(|a| matches!(a, Actions::Add(..))));
+        assert_eq!(
+            Spotify::new(before, opts.max_n).apply(&actions),
+            vec!["black", "white", "pink", "red", "blue", "green"]
+        );
+    }
 }
@@ -4,7 +4,7 @@ use regex::Regex;
 
 use url::Url;
 
-use crate::types::{Link, Service};
+use crate::types::{Kind, Link, Service};
 
 pub fn extract_links(content: &str) -> Vec<Link> {
     extract_urls(content)
@@ -56,15 +56,20 @@ pub fn parse_url(url: &Url) -> Option<Link> {
         entry.service = Service::Spotify;
 
         //https://regex101.com/r/PvfZk6/1
+        // the old `user/<name>/playlist/<id>` links are playlists, only a bare `user/<name>` is a profile.
+        // user names aren't base62 like the other ids, they can have dots and dashes
         lazy_static::lazy_static! {
-            static ref RE: Regex = Regex::new(r"(?m)(artist|album|track|user|playlist)/([A-Za-z0-9]+)$").unwrap();
+            static ref RE: Regex = Regex::new(r"(?m)(artist|album|track|playlist)/([A-Za-z0-9]+)/?$|user/([^/]+)/?$").unwrap();
         };
 
         match RE.captures(url.path()) {
             Some(v) => {
-                entry.id = v.get(2).unwrap().as_str().to_string(); //??? should we convert to spotify:<type>:<id> format?
-                entry.kind = Some(v.get(1).unwrap().as_str().parse().unwrap());
-                entry.id = format!("spotify:{}:{}", entry.kind.unwrap().to_string(), &entry.id);
+                let (kind, id) = match v.get(3) {
+                    Some(user) => (Kind::User, user.as_str()),
+                    None => (v[1].parse().unwrap(), v.get(2).unwrap().as_str()),
+                };
+                entry.kind = Some(kind);
+                entry.id = format!("spotify:{}:{}", kind, id);
             }
             None => {
                 dbg!(url.to_string());
@@ -83,7 +88,12 @@ pub fn parse_url(url: &Url) -> Option<Link> {
 
 #[cfg(test)]
 mod tests {
-    use crate::utils::links::{extract_urls, parse_url};
+    use url::Url;
+
+    use crate::{
+        types::Kind,
+        utils::links::{extract_urls, parse_url},
+    };
 
     #[test]
     fn url_extract() {
@@ -108,4 +118,35 @@ mod tests {
             assert!(link.is_some(), "invalid {}", url);
         }
     }
+
+    #[test]
+    fn spotify_kinds() {
+        let cases = [
+            (
+                "https://play.spotify.com/user/spotifydiscover/playlist/0vL3R9wDeAwmXTTuRATa14",
+                Kind::Playlist,
+                "spotify:playlist:0vL3R9wDeAwmXTTuRATa14",
+            ),
+            (
+                "https://open.spotify.com/user/spotifydiscover",
+                Kind::User,
+                "spotify:user:spotifydiscover",
+            ),
+            (
+                "https://open.spotify.com/user/some.one-else/",
+                Kind::User,
+                "spotify:user:some.one-else",
+            ),
+            (
+                "https://open.spotify.com/track/1TZ3z6TBztuY0TLUlJZ8R7?si=abc",
+                Kind::Track,
+                "spotify:track:1TZ3z6TBztuY0TLUlJZ8R7",
+            ),
+        ];
+        for (url, kind, id) in cases {
+            let link = parse_url(&Url::parse(url).unwrap()).unwrap();
+            assert_eq!(link.kind, Some(kind), "{}", url);
+            assert_eq!(link.id, id, "{}", url);
+        }
+    }
 }